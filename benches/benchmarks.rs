@@ -1,6 +1,6 @@
 use bitpart::{
     metric::{Euclidean, Metric},
-    BitPart, Builder,
+    BitPart, Builder, Cullable,
 };
 use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
 use rayon::prelude::*;
@@ -244,7 +244,7 @@ fn nn_query_inner<T>(
             |(points_inner, thresholds_inner)| {
                 for (query, threshold) in points_inner
                     .into_iter()
-                    .zip(thresholds_inner.into_iter())
+                    .zip(thresholds_inner)
                     .skip(skip_first)
                     .take(n)
                 {
@@ -266,7 +266,7 @@ fn nn_query_inner<T>(
             |(points_inner, thresholds_inner)| {
                 for (query, threshold) in points_inner
                     .into_iter()
-                    .zip(thresholds_inner.into_iter())
+                    .zip(thresholds_inner)
                     .skip(skip_first)
                     .take(n)
                 {
@@ -315,7 +315,7 @@ fn nn_query_inner<T>(
     for cull_threshold in [0.95, 0.9, 0.85, 0.8] {
         // Cull by popcnt
         let mut bitpart_cull = builder.clone().build_parallel(Some(8192));
-        bitpart_cull.cull_by_popcnt(cull_threshold);
+        bitpart_cull.cull_by_popcnt(cull_threshold).unwrap();
         group.bench_function(BenchmarkId::new("cull_pop", cull_threshold), |bn| {
             bn.iter(|| {
                 for (query, threshold) in points
@@ -333,7 +333,7 @@ fn nn_query_inner<T>(
 
         // Cull by similarity
         let mut bitpart_cull = builder.clone().build_parallel(Some(8192));
-        bitpart_cull.cull_by_similarity(cull_threshold);
+        bitpart_cull.cull_by_similarity(cull_threshold).unwrap();
         group.bench_function(BenchmarkId::new("cull_sim", cull_threshold), |bn| {
             bn.iter(|| {
                 for (query, threshold) in points
@@ -351,8 +351,8 @@ fn nn_query_inner<T>(
 
         // Cull both
         let mut bitpart_cull = builder.clone().build_parallel(Some(8192));
-        bitpart_cull.cull_by_popcnt(cull_threshold);
-        bitpart_cull.cull_by_similarity(cull_threshold);
+        bitpart_cull.cull_by_popcnt(cull_threshold).unwrap();
+        bitpart_cull.cull_by_similarity(cull_threshold).unwrap();
         group.bench_function(BenchmarkId::new("cull_all", cull_threshold), |bn| {
             bn.iter(|| {
                 for (query, threshold) in points
@@ -484,7 +484,7 @@ pub fn nn_setup_time(c: &mut Criterion) {
     let mut group = c.benchmark_group("setup");
 
     for sz in (1..=10).map(|x| x * 10000) {
-        let points_subset = points.iter().cloned().take(sz).collect::<Vec<_>>();
+        let points_subset = points.iter().take(sz).cloned().collect::<Vec<_>>();
         let builder = Builder::new(points_subset, 40);
 
         group.bench_function(BenchmarkId::new("seq", sz), |bn| {
@@ -3,8 +3,9 @@ use nom::{
     combinator::verify,
     multi::{many1, separated_list0},
     number::complete::double,
-    IResult,
+    Finish, IResult,
 };
+use std::io::{self, BufRead};
 
 /// The configuration of the dataset.
 /// This corresponds to the first line in an `.ascii` file.
@@ -83,6 +84,70 @@ pub fn parse_array<const N: usize>(input: &str) -> IResult<&str, (FileConfig, Ve
     Ok((input, (file_config, vectors)))
 }
 
+/// Read an `.ascii` file one vector at a time, without holding the whole file in memory.
+///
+/// The configuration line is read immediately. Vectors are parsed as the returned iterator is advanced. A vector which
+/// cannot be parsed or does not have the configured number of dimensions is returned as an [`InvalidData`](io::ErrorKind::InvalidData)
+/// error, and a file with fewer vectors than configured ends with an [`UnexpectedEof`](io::ErrorKind::UnexpectedEof) error.
+pub fn read<R: BufRead>(mut reader: R) -> io::Result<(FileConfig, Vectors<R>)> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let (_, file_config) = config(&line)
+        .finish()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    Ok((
+        file_config,
+        Vectors {
+            reader,
+            file_config,
+            read: 0,
+            line,
+        },
+    ))
+}
+
+/// Iterator over the vectors of an `.ascii` file, returned by [`read`].
+#[derive(Debug)]
+pub struct Vectors<R> {
+    reader: R,
+    file_config: FileConfig,
+    read: u64,
+    line: String,
+}
+
+impl<R: BufRead> Iterator for Vectors<R> {
+    type Item = io::Result<Vec<f64>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.read == self.file_config.lines {
+            return None;
+        }
+        self.read += 1;
+
+        self.line.clear();
+        match self.reader.read_line(&mut self.line) {
+            Ok(0) => return Some(Err(io::ErrorKind::UnexpectedEof.into())),
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        if !self.line.ends_with('\n') {
+            self.line.push('\n');
+        }
+
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        Some(match vector(&self.line).finish() {
+            Ok((_, v)) if v.len() == self.file_config.dimensions as usize => Ok(v),
+            Ok((_, v)) => Err(invalid(format!(
+                "expected {} dimensions, found {}",
+                self.file_config.dimensions,
+                v.len()
+            ))),
+            Err(e) => Err(invalid(e.to_string())),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{colors::COLORS_DIMENSION, nasa::NASA_DIMENSION};
@@ -140,6 +205,24 @@ mod tests {
         assert_eq!(config.lines as usize, vectors.len());
     }
 
+    #[test]
+    fn nasa_read() {
+        let (config, vectors) = read(NASA.as_bytes()).unwrap();
+        let vectors = vectors.collect::<io::Result<Vec<_>>>().unwrap();
+
+        assert_eq!(config.dimensions, 20);
+        assert_eq!(vectors, parse(NASA).unwrap().1 .1);
+
+        // Missing vectors are reported.
+        let (_, vectors) = read("2 2 0\n1.0 2.0\n".as_bytes()).unwrap();
+        let vectors = vectors.collect::<Vec<_>>();
+        assert_eq!(vectors.len(), 2);
+        assert_eq!(
+            vectors[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
     #[test]
     #[should_panic]
     fn colors_const_wrong_dim() {
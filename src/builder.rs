@@ -1,7 +1,21 @@
-use crate::{metric::Metric, Sequential};
+#[cfg(feature = "par")]
+use crate::exclusions::ExclusionSync;
+#[cfg(feature = "disk")]
+use crate::DiskError;
+use crate::{
+    exclusions::{Exclusion, ZoneDef},
+    metric::{Metric, Supermetric},
+    random::Rng,
+    Sequential,
+};
+use itertools::Itertools;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
+use thiserror::Error;
 
 /// Builder for the BitPart data structure.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Builder<T> {
     pub(crate) dataset: Vec<T>,
 
@@ -10,6 +24,75 @@ pub struct Builder<T> {
 
     pub(crate) four_point: bool,
     pub(crate) ref_points: u64,
+    pub(crate) ref_point_selection: RefPointSelection,
+    pub(crate) calibration: Option<(usize, u64)>,
+    pub(crate) rings: Option<usize>,
+    pub(crate) sheet_balance: Option<(usize, u64)>,
+
+    pub(crate) exclusions: Vec<Arc<dyn Exclusion<T>>>,
+    #[cfg(feature = "par")]
+    pub(crate) exclusions_sync: Vec<Arc<dyn ExclusionSync<T>>>,
+}
+
+impl<T> fmt::Debug for Builder<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("dataset", &self.dataset)
+            .field("radius_increment", &self.radius_increment)
+            .field("mean_distance", &self.mean_distance)
+            .field("four_point", &self.four_point)
+            .field("ref_points", &self.ref_points)
+            .field("ref_point_selection", &self.ref_point_selection)
+            .field("calibration", &self.calibration)
+            .field("rings", &self.rings)
+            .field("sheet_balance", &self.sheet_balance)
+            .field("exclusions", &self.exclusions.len())
+            .finish()
+    }
+}
+
+/// Strategy used to choose reference points from the dataset.
+///
+/// Every exclusion zone is defined in terms of reference points, so their placement has a large effect on how well zones
+/// exclude points. All strategies which involve randomness are seeded, so the same dataset and seed always produce the same reference points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum RefPointSelection {
+    /// Use the first `ref_points` points of the dataset. This is the default.
+    ///
+    /// This is only a good choice if the dataset is already in random order.
+    #[default]
+    First,
+    /// Sample reference points uniformly at random, without replacement.
+    Random {
+        /// Seed for the random number generator.
+        seed: u64,
+    },
+    /// Farthest-first traversal.
+    ///
+    /// The first reference point is chosen at random. Each subsequent reference point is the point whose distance to its closest
+    /// reference point is the largest, which spreads reference points out across the dataset.
+    /// This requires `ref_points * dataset.len()` distance calculations.
+    FarthestFirst {
+        /// Seed for the random number generator.
+        seed: u64,
+    },
+    /// Maximum variance heuristic.
+    ///
+    /// `sample_size` points are sampled at random, and for each of them the variance of its distances to the rest of the sample is calculated.
+    /// The points with the highest variance are chosen as reference points, as they tend to separate the dataset well.
+    /// This requires `sample_size * sample_size` distance calculations.
+    ///
+    /// `sample_size` is clamped to be at least `ref_points` and at most the size of the dataset.
+    MaxVariance {
+        /// Seed for the random number generator.
+        seed: u64,
+        /// Number of points to consider.
+        sample_size: usize,
+    },
 }
 
 impl<T> Builder<T>
@@ -19,20 +102,21 @@ where
     /// Create a new `BitPartBuilder` from a dataset.
     ///
     /// # Panics
-    /// This function will panic if `ref_points` is greater than the size of the dataset, or is zero.
+    /// This function will panic if the dataset is empty, or if `ref_points` is greater than the size of the dataset, or is zero.
+    /// Use [`try_new`](crate::Builder::try_new) to handle these cases instead.
     pub fn new(dataset: impl IntoIterator<Item = T>, ref_points: u64) -> Self {
-        let dataset = dataset.into_iter().collect::<Vec<_>>();
-
-        assert!(ref_points as usize <= dataset.len());
-        assert!(ref_points > 0);
+        Self::try_new(dataset, ref_points).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        Self {
-            dataset,
-            mean_distance: 1.81,
-            radius_increment: 0.3,
-            four_point: true,
-            ref_points,
-        }
+    /// Create a new `BitPartBuilder` from a dataset, returning an error if the dataset is empty, or if `ref_points` is
+    /// greater than the size of the dataset, or is zero.
+    pub fn try_new(
+        dataset: impl IntoIterator<Item = T>,
+        ref_points: u64,
+    ) -> Result<Self, BuildError> {
+        let builder = Self::unchecked(dataset.into_iter().collect(), ref_points);
+        builder.validate()?;
+        Ok(builder)
     }
 
     /// Set the mean distance used when generating ball exclusion zones.
@@ -46,6 +130,9 @@ where
     /// mean_distance - radius_increment
     /// mean_distance - 2 * radius_increment
     /// ```
+    ///
+    /// This value is ignored if the builder is [calibrated](crate::Builder::calibrate) or uses
+    /// [per-point radii](crate::Builder::per_point_radii).
     pub fn mean_distance(mut self, mean_distance: f64) -> Self {
         self.mean_distance = mean_distance;
         self
@@ -60,27 +147,699 @@ where
         self
     }
 
-    /// Set whether to use four-point or three-point method for sheet exclusions.
-    #[deprecated(note = "This option does nothing.")]
-    pub fn four_point(mut self, four_point: bool) -> Self {
-        self.four_point = four_point;
+    /// Set the number of ref points
+    ///
+    /// `ref_points` must be greater than zero, and no greater than the size of the dataset. This is checked when the data structure is built.
+    pub fn ref_points(mut self, ref_points: u64) -> Self {
+        self.ref_points = ref_points;
         self
     }
 
-    /// Set the number of ref points
+    /// Calibrate the radii of ball exclusion zones from the dataset, instead of using [`mean_distance`](crate::Builder::mean_distance)
+    /// and [`radius_increment`](crate::Builder::radius_increment).
     ///
-    /// # Panics
-    /// This function will panic if `ref_points` is greater than the size of the dataset, or is zero.
-    pub fn ref_points(mut self, ref_points: u64) -> Self {
-        assert!(ref_points as usize <= self.dataset.len());
-        assert!(ref_points > 0);
+    /// When the data structure is built, `sample_size` points are sampled at random using `seed`, and the distance from every reference point
+    /// to every sampled point is calculated. The five radii are then set to the quantiles which split this distribution into six equal parts,
+    /// so that each ball exclusion divides the dataset in a known proportion regardless of its scale.
+    ///
+    /// `sample_size` is clamped to the size of the dataset. It must be greater than zero, or building returns [`BuildError::EmptySample`].
+    pub fn calibrate(mut self, sample_size: usize, seed: u64) -> Self {
+        self.calibration = Some((sample_size, seed));
+        self
+    }
 
-        self.ref_points = ref_points;
+    /// Calculate ball radii separately for each reference point, creating `rings` ball exclusions around each of them.
+    ///
+    /// A reference point at the edge of the dataset has a very different distance profile from one in the middle, so a single set
+    /// of radii tends to produce balls which contain almost all or almost none of the dataset. Instead, the radii for each reference point
+    /// are set to the quantiles which split *its own* distances to the dataset into `rings + 1` equal parts.
+    ///
+    /// If the builder is [calibrated](crate::Builder::calibrate), distances are measured to the calibration sample. Otherwise, distances
+    /// are measured to the whole dataset.
+    ///
+    /// `rings` must be greater than zero, or building returns [`BuildError::NoRings`].
+    pub fn per_point_radii(mut self, rings: usize) -> Self {
+        self.rings = Some(rings);
+        self
+    }
+
+    /// Offset sheet exclusions so that each of them splits the dataset roughly in half.
+    ///
+    /// By default, every sheet exclusion lies on the perpendicular bisector between its two reference points `a` and `b`. If the dataset is
+    /// skewed towards one of them, most points end up on the same side of the sheet. Instead, `sample_size` points are sampled at random
+    /// using `seed`, and each sheet's offset is set to the median of `d(a, x) - d(b, x)` over the sampled points `x`.
+    /// For [four-point](crate::Builder::four_point) sheets, the median of each point's projection onto the line between `a` and `b` is used instead.
+    ///
+    /// `sample_size` is clamped to the size of the dataset.
+    pub fn balance_sheets(mut self, sample_size: usize, seed: u64) -> Self {
+        self.sheet_balance = Some((sample_size, seed));
+        self
+    }
+
+    /// Add custom exclusion zones to the data structure, in addition to the ones generated from reference points.
+    ///
+    /// Zones added with this function are not `Send` or `Sync`, so they can only be used by [`build`](crate::Builder::build).
+    /// Use [`with_exclusions_sync`](crate::Builder::with_exclusions_sync) for zones which should also be usable by the parallel variants.
+    pub fn with_exclusions(mut self, exclusions: Vec<Box<dyn Exclusion<T>>>) -> Self {
+        self.exclusions
+            .extend(exclusions.into_iter().map(Arc::<dyn Exclusion<T>>::from));
+        self
+    }
+
+    /// Set the strategy used to choose reference points.
+    /// By default, [`RefPointSelection::First`] is used.
+    pub fn ref_point_selection(mut self, ref_point_selection: RefPointSelection) -> Self {
+        self.ref_point_selection = ref_point_selection;
         self
     }
 
     /// Build the BitPart.
+    ///
+    /// # Panics
+    /// This function will panic if [`try_build`](crate::Builder::try_build) would return an error.
     pub fn build<'a>(self) -> Sequential<'a, T> {
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Build the BitPart, returning an error if the builder's configuration is invalid or the metric returns NaN.
+    pub fn try_build<'a>(self) -> Result<Sequential<'a, T>, BuildError> {
         Sequential::setup(self)
     }
 }
+
+#[cfg(feature = "par")]
+impl<T> Builder<T>
+where
+    for<'a> T: Metric + Send + Sync + 'a,
+{
+    /// Add custom exclusion zones to the data structure, in addition to the ones generated from reference points.
+    ///
+    /// Unlike [`with_exclusions`](crate::Builder::with_exclusions), these zones can be used by every variant of BitPart.
+    pub fn with_exclusions_sync(mut self, exclusions: Vec<Box<dyn ExclusionSync<T>>>) -> Self {
+        for ez in exclusions {
+            let ez = Arc::<dyn ExclusionSync<T>>::from(ez);
+            self.exclusions
+                .push(Arc::new(Arc::clone(&ez)) as Arc<dyn Exclusion<T>>);
+            self.exclusions_sync.push(ez);
+        }
+        self
+    }
+}
+
+impl<T> Builder<T>
+where
+    for<'a> T: Supermetric + 'a,
+{
+    /// Set whether to use four-point or three-point method for sheet exclusions.
+    /// By default, the three-point method is used.
+    ///
+    /// Four-point exclusion is only valid in [supermetric](crate::metric::Supermetric) spaces. It excludes points using
+    /// their projection onto the line between two reference points, which is usually much tighter than the three-point bound.
+    ///
+    /// # Compatibility
+    /// Previously, this option was available for every metric, defaulted to `true`, and did nothing: three-point sheets were always used.
+    /// It is now only available when `T` is a [`Supermetric`](crate::metric::Supermetric), and defaults to `false`, so that data
+    /// structures built without calling it keep the same sheet exclusions as before. Calls made for other metrics had no effect and
+    /// can be removed, and calls with `true` for supermetrics now enable four-point exclusion.
+    pub fn four_point(mut self, four_point: bool) -> Self {
+        self.four_point = four_point;
+        self
+    }
+}
+
+impl<T> Builder<T>
+where
+    T: Metric,
+{
+    /// Create a builder with default settings, without checking that `dataset` and `ref_points` are valid.
+    pub(crate) fn unchecked(dataset: Vec<T>, ref_points: u64) -> Self {
+        Self {
+            dataset,
+            mean_distance: 1.81,
+            radius_increment: 0.3,
+            four_point: false,
+            ref_points,
+            ref_point_selection: RefPointSelection::default(),
+            calibration: None,
+            rings: None,
+            sheet_balance: None,
+            exclusions: vec![],
+            #[cfg(feature = "par")]
+            exclusions_sync: vec![],
+        }
+    }
+
+    /// Whether any custom exclusion zones were added which cannot be used by the parallel variants.
+    #[cfg(feature = "par")]
+    pub(crate) fn has_unsync_exclusions(&self) -> bool {
+        self.exclusions.len() != self.exclusions_sync.len()
+    }
+
+    /// Check that the dataset and number of reference points are valid.
+    pub(crate) fn validate(&self) -> Result<(), BuildError> {
+        if self.dataset.is_empty() {
+            Err(BuildError::EmptyDataset)
+        } else if self.ref_points == 0 {
+            Err(BuildError::NoRefPoints)
+        } else if self.ref_points as usize > self.dataset.len() {
+            Err(BuildError::TooManyRefPoints {
+                ref_points: self.ref_points,
+                dataset_len: self.dataset.len(),
+            })
+        } else if self.rings == Some(0) {
+            Err(BuildError::NoRings)
+        } else if matches!(self.calibration, Some((0, _))) {
+            Err(BuildError::EmptySample)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Choose reference points from the dataset, according to the configured [`RefPointSelection`].
+    pub(crate) fn select_ref_points(&self) -> Vec<T> {
+        self.ref_point_indices()
+            .into_iter()
+            .map(|idx| self.dataset[idx].clone())
+            .collect()
+    }
+
+    /// Definitions of the exclusion zones to create: balls, then sheets, then custom zones.
+    pub(crate) fn zone_defs(&self, ref_points: &[T]) -> Result<Vec<ZoneDef>, BuildError> {
+        let mut zones = self
+            .ball_radii(ref_points)?
+            .into_iter()
+            .enumerate()
+            .flat_map(|(point, radii)| {
+                radii
+                    .into_iter()
+                    .map(move |radius| ZoneDef::Ball { point, radius })
+            })
+            .collect::<Vec<_>>();
+
+        zones.extend(self.sheets(ref_points)?.into_iter().map(
+            |(a, b, offset)| match self.four_point {
+                true => ZoneDef::Hilbert { a, b, offset },
+                false => ZoneDef::Sheet { a, b, offset },
+            },
+        ));
+        zones.extend(self.exclusions.iter().map(|_| ZoneDef::Custom));
+        Ok(zones)
+    }
+
+    /// The parameters this builder was configured with.
+    pub(crate) fn params(&self) -> Params {
+        Params {
+            radius_increment: self.radius_increment,
+            mean_distance: self.mean_distance,
+            four_point: self.four_point,
+            ref_points: self.ref_points,
+            ref_point_selection: self.ref_point_selection,
+            calibration: self.calibration,
+            rings: self.rings,
+            sheet_balance: self.sheet_balance,
+        }
+    }
+
+    /// Radii of the ball exclusions to create around each reference point.
+    pub(crate) fn ball_radii(&self, ref_points: &[T]) -> Result<Vec<Vec<f64>>, BuildError> {
+        let sample = || match self.calibration {
+            Some((sample_size, seed)) => self.sample(sample_size, seed),
+            None => self.dataset.iter().collect(),
+        };
+
+        let radii = if let Some(rings) = self.rings {
+            let sample = sample();
+            ref_points
+                .iter()
+                .map(|ref_point| {
+                    let distances = sample.iter().map(|pt| ref_point.distance(pt)).collect();
+                    check_nan(distances).map(|distances| quantiles(distances, rings))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            vec![self.shared_ball_radii(ref_points, sample)?; ref_points.len()]
+        };
+
+        match radii.iter().flatten().find(|r| !r.is_finite()) {
+            Some(radius) => Err(BuildError::NonFiniteRadius(*radius)),
+            None => Ok(radii),
+        }
+    }
+
+    /// Radii of the ball exclusions to create around every reference point, if they are not calculated per point.
+    fn shared_ball_radii<'s>(
+        &'s self,
+        ref_points: &[T],
+        sample: impl FnOnce() -> Vec<&'s T>,
+    ) -> Result<Vec<f64>, BuildError> {
+        match self.calibration {
+            Some(_) => {
+                let distances = ref_points
+                    .iter()
+                    .cartesian_product(sample())
+                    .map(|(ref_point, pt)| ref_point.distance(pt))
+                    .collect();
+
+                Ok(quantiles(check_nan(distances)?, 5))
+            }
+            None => Ok(vec![
+                self.mean_distance - 2.0 * self.radius_increment,
+                self.mean_distance - self.radius_increment,
+                self.mean_distance,
+                self.mean_distance + self.radius_increment,
+                self.mean_distance + 2.0 * self.radius_increment,
+            ]),
+        }
+    }
+
+    /// Pairs of reference points (by index) to create sheet exclusions for, along with the offset of each sheet.
+    ///
+    /// Pairs of coincident reference points, which can be chosen when the dataset contains duplicate points, are skipped: every point
+    /// is equally far from both of them, so their sheet would not split the dataset.
+    pub(crate) fn sheets(&self, ref_points: &[T]) -> Result<Vec<(usize, usize, f64)>, BuildError> {
+        let pairs = ref_points
+            .iter()
+            .enumerate()
+            .combinations(2)
+            .filter(|x| x[0].1.distance(x[1].1) != 0.0);
+
+        let sheets = match self.sheet_balance {
+            Some((sample_size, seed)) => {
+                let sample = self.sample(sample_size, seed);
+                let distances = ref_points
+                    .iter()
+                    .map(|ref_point| {
+                        sample
+                            .iter()
+                            .map(|pt| ref_point.distance(pt))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+
+                pairs
+                    .map(|x| {
+                        let (i, a) = x[0];
+                        let (j, b) = x[1];
+                        let differences = distances[i]
+                            .iter()
+                            .zip(distances[j].iter())
+                            .map(|(da, db)| self.sheet_position(a.distance(b), *da, *db))
+                            .collect();
+
+                        (
+                            i,
+                            j,
+                            quantiles(differences, 1).first().copied().unwrap_or(0.0),
+                        )
+                    })
+                    .collect()
+            }
+            None => pairs.map(|x| (x[0].0, x[1].0, 0.0)).collect::<Vec<_>>(),
+        };
+
+        if sheets.iter().any(|&(a, b, offset)| {
+            offset.is_nan() || ref_points[a].distance(&ref_points[b]).is_nan()
+        }) {
+            return Err(BuildError::NanDistance);
+        }
+        Ok(sheets)
+    }
+
+    /// Position of a point relative to the perpendicular bisector of two reference points `a` and `b` which are `ab` apart,
+    /// given its distances `da` and `db` to them. This is the quantity which sheet offsets are measured in.
+    fn sheet_position(&self, ab: f64, da: f64, db: f64) -> f64 {
+        if self.four_point {
+            (da.powi(2) - db.powi(2)) / (2.0 * ab)
+        } else {
+            da - db
+        }
+    }
+
+    /// Sample up to `sample_size` points from the dataset at random, without replacement.
+    fn sample(&self, sample_size: usize, seed: u64) -> Vec<&T> {
+        let len = self.dataset.len();
+
+        Rng::new(seed)
+            .sample(len, sample_size)
+            .into_iter()
+            .map(|idx| &self.dataset[idx])
+            .collect()
+    }
+
+    /// Indices of the points in the dataset to use as reference points.
+    pub(crate) fn ref_point_indices(&self) -> Vec<usize> {
+        let len = self.dataset.len();
+        let n = self.ref_points as usize;
+
+        match self.ref_point_selection {
+            RefPointSelection::First => (0..n).collect(),
+            RefPointSelection::Random { seed } => Rng::new(seed).sample(len, n),
+            RefPointSelection::FarthestFirst { seed } => {
+                let first = Rng::new(seed).below(len);
+                let mut chosen = vec![first];
+
+                // Distance from each point to its closest reference point.
+                let mut closest = self
+                    .dataset
+                    .iter()
+                    .map(|pt| pt.distance(&self.dataset[first]))
+                    .collect::<Vec<_>>();
+                closest[first] = f64::NEG_INFINITY;
+
+                while chosen.len() < n {
+                    let (next, _) = closest
+                        .iter()
+                        .enumerate()
+                        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                        .unwrap();
+
+                    for (dist, pt) in closest.iter_mut().zip(self.dataset.iter()) {
+                        *dist = dist.min(pt.distance(&self.dataset[next]));
+                    }
+                    closest[next] = f64::NEG_INFINITY;
+                    chosen.push(next);
+                }
+
+                chosen
+            }
+            RefPointSelection::MaxVariance { seed, sample_size } => {
+                let sample_size = sample_size.clamp(n, len);
+                let sample = Rng::new(seed).sample(len, sample_size);
+
+                let mut variances = sample
+                    .iter()
+                    .map(|&i| {
+                        let distances = sample
+                            .iter()
+                            .filter(|&&j| i != j)
+                            .map(|&j| self.dataset[i].distance(&self.dataset[j]))
+                            .collect::<Vec<_>>();
+
+                        (i, variance(&distances))
+                    })
+                    .collect::<Vec<_>>();
+
+                variances.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+                variances.into_iter().take(n).map(|(i, _)| i).collect()
+            }
+        }
+    }
+}
+
+/// Parameters a data structure was built with. They are kept alongside it so that they can be saved.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) struct Params {
+    pub(crate) radius_increment: f64,
+    pub(crate) mean_distance: f64,
+    pub(crate) four_point: bool,
+    pub(crate) ref_points: u64,
+    pub(crate) ref_point_selection: RefPointSelection,
+    pub(crate) calibration: Option<(usize, u64)>,
+    pub(crate) rings: Option<usize>,
+    pub(crate) sheet_balance: Option<(usize, u64)>,
+}
+
+/// Returns `distances`, or an error if any of them is NaN.
+fn check_nan(distances: Vec<f64>) -> Result<Vec<f64>, BuildError> {
+    match distances.iter().any(|d| d.is_nan()) {
+        true => Err(BuildError::NanDistance),
+        false => Ok(distances),
+    }
+}
+
+/// Returns the `n` quantiles which split `values` into `n + 1` parts of (roughly) equal size.
+fn quantiles(mut values: Vec<f64>, n: usize) -> Vec<f64> {
+    if values.is_empty() {
+        return vec![];
+    }
+
+    values.sort_by(|a, b| a.total_cmp(b));
+    let last = (values.len() - 1) as f64;
+
+    (1..=n)
+        .map(|i| values[(last * i as f64 / (n + 1) as f64).round() as usize])
+        .collect()
+}
+
+fn variance(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64
+}
+
+/// Errors that can be encountered while building a BitPart.
+#[derive(Debug, Error)]
+pub enum BuildError {
+    /// The dataset contains no points.
+    #[error("dataset is empty")]
+    EmptyDataset,
+    /// Zero reference points were requested.
+    #[error("at least one reference point is required")]
+    NoRefPoints,
+    /// More reference points were requested than there are points in the dataset.
+    #[error(
+        "{ref_points} reference points requested, but the dataset only has {dataset_len} points"
+    )]
+    TooManyRefPoints {
+        /// Number of reference points requested.
+        ref_points: u64,
+        /// Number of points in the dataset.
+        dataset_len: usize,
+    },
+    /// [Per-point radii](crate::Builder::per_point_radii) were requested with zero rings.
+    #[error("at least one ring is required for per-point radii")]
+    NoRings,
+    /// A [calibration](crate::Builder::calibrate) sample of zero points was requested, so no ball radii could be derived from it.
+    #[error("calibration sample must contain at least one point")]
+    EmptySample,
+    /// A ball exclusion zone would have an infinite or NaN radius.
+    ///
+    /// This means [`mean_distance`](crate::Builder::mean_distance) or [`radius_increment`](crate::Builder::radius_increment)
+    /// is not finite, or the dataset contains infinite distances.
+    #[error("ball radius {0} is not finite")]
+    NonFiniteRadius(f64),
+    /// The metric returned NaN for a distance calculated while building exclusion zones.
+    #[error("distance is NaN")]
+    NanDistance,
+    /// A block size of zero was requested.
+    #[error("block size must be greater than zero")]
+    ZeroBlockSize,
+    /// Exclusion zones added with [`with_exclusions`](crate::Builder::with_exclusions) cannot be used by the parallel variants,
+    /// as they are not guaranteed to be `Send` and `Sync`.
+    #[error("exclusion zones are not thread-safe")]
+    UnsyncExclusions,
+    /// Generic IO error. This means the directory for partitioning data could not be created.
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    /// Partitioning data could not be written to disk.
+    #[cfg(feature = "disk")]
+    #[error("could not write partitioning data")]
+    Disk(#[from] DiskError),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metric::Euclidean;
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn points() -> Vec<Euclidean<[f64; 2]>> {
+        (0..1000)
+            .map(|i| Euclidean::new([(i % 40) as f64, (i / 40) as f64]))
+            .collect()
+    }
+
+    #[test]
+    fn ref_point_selection() {
+        for selection in [
+            RefPointSelection::First,
+            RefPointSelection::Random { seed: 42 },
+            RefPointSelection::FarthestFirst { seed: 42 },
+            RefPointSelection::MaxVariance {
+                seed: 42,
+                sample_size: 200,
+            },
+        ] {
+            let builder = Builder::new(points(), 40).ref_point_selection(selection);
+            let indices = builder.ref_point_indices();
+
+            // Reference points are distinct, and the same seed gives the same reference points.
+            assert_eq!(indices.len(), 40);
+            assert_eq!(indices.iter().collect::<HashSet<_>>().len(), 40);
+            assert_eq!(indices, builder.ref_point_indices());
+        }
+    }
+
+    #[test]
+    fn farthest_first_corners() {
+        let builder = Builder::new(points(), 4)
+            .ref_point_selection(RefPointSelection::FarthestFirst { seed: 0 });
+
+        // The grid is 40 by 25, so the point farthest from the first reference point is always a corner.
+        let corners = [0, 39, 960, 999];
+        let indices = builder.ref_point_indices();
+        assert!(corners.contains(&indices[1]));
+    }
+
+    #[test]
+    fn calibrate() {
+        let points = points();
+        let builder = Builder::new(points.clone(), 40).calibrate(1000, 0);
+        let ref_points = builder.select_ref_points();
+        let radii = builder.ball_radii(&ref_points).unwrap();
+
+        assert_eq!(radii.len(), 40);
+        assert!(radii.iter().all(|r| r == &radii[0]));
+        assert!(radii[0].windows(2).all(|w| w[0] <= w[1]));
+
+        // The whole dataset was sampled, so each radius should split the distances in its expected proportion.
+        let distances = ref_points
+            .iter()
+            .cartesian_product(points.iter())
+            .map(|(r, pt)| r.distance(pt))
+            .collect::<Vec<_>>();
+
+        for (i, radius) in radii[0].iter().enumerate() {
+            let inside = distances.iter().filter(|d| *d < radius).count() as f64;
+            let expected = (i + 1) as f64 / 6.0;
+            assert!((inside / distances.len() as f64 - expected).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn per_point_radii() {
+        let points = points();
+        let builder = Builder::new(points.clone(), 40)
+            .ref_point_selection(RefPointSelection::Random { seed: 0 })
+            .per_point_radii(3);
+        let ref_points = builder.select_ref_points();
+        let radii = builder.ball_radii(&ref_points).unwrap();
+
+        assert_eq!(radii.len(), 40);
+
+        // Each ring should split the dataset in its expected proportion, for every reference point.
+        for (ref_point, radii) in ref_points.iter().zip(radii) {
+            assert_eq!(radii.len(), 3);
+
+            for (i, radius) in radii.iter().enumerate() {
+                let inside = points
+                    .iter()
+                    .filter(|pt| ref_point.distance(pt) < *radius)
+                    .count() as f64;
+                let expected = (i + 1) as f64 / 4.0;
+                assert!((inside / points.len() as f64 - expected).abs() < 0.05);
+            }
+        }
+    }
+
+    #[test]
+    fn balance_sheets() {
+        // Skew the dataset so that almost every point is closer to the last reference point than the first.
+        let points = points()
+            .into_iter()
+            .map(|pt| Euclidean::new([pt[0] * pt[0], pt[1]]))
+            .collect::<Vec<_>>();
+        let builder = Builder::new(points.clone(), 4)
+            .ref_point_selection(RefPointSelection::FarthestFirst { seed: 0 })
+            .balance_sheets(1000, 0);
+        let ref_points = builder.select_ref_points();
+        let sheets = builder.sheets(&ref_points).unwrap();
+
+        assert_eq!(sheets.len(), 6);
+
+        for (a, b, offset) in sheets {
+            let (a, b) = (&ref_points[a], &ref_points[b]);
+            let inside = points
+                .iter()
+                .filter(|pt| a.distance(pt) - b.distance(pt) - offset < 0.0)
+                .count() as f64;
+            assert!((inside / points.len() as f64 - 0.5).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn duplicate_ref_points() {
+        let mut points = points();
+        points[1] = points[0].clone();
+        let builder = Builder::new(points, 10)
+            .four_point(true)
+            .balance_sheets(1000, 0);
+        let ref_points = builder.select_ref_points();
+        let sheets = builder.sheets(&ref_points).unwrap();
+
+        assert_eq!(sheets.len(), 45 - 1);
+        assert!(!sheets.iter().any(|&(a, b, _)| (a, b) == (0, 1)));
+        assert!(sheets.iter().all(|(_, _, offset)| offset.is_finite()));
+        assert_eq!(
+            crate::BitPart::zones(&builder.try_build().unwrap()),
+            10 * 5 + 45 - 1
+        );
+    }
+
+    #[test]
+    fn try_new() {
+        assert!(matches!(
+            Builder::try_new(Vec::<Euclidean<[f64; 2]>>::new(), 1),
+            Err(BuildError::EmptyDataset)
+        ));
+        assert!(matches!(
+            Builder::try_new(points(), 0),
+            Err(BuildError::NoRefPoints)
+        ));
+        assert!(matches!(
+            Builder::try_new(points(), 1001),
+            Err(BuildError::TooManyRefPoints {
+                ref_points: 1001,
+                dataset_len: 1000
+            })
+        ));
+        assert!(Builder::try_new(points(), 1000).is_ok());
+    }
+
+    #[test]
+    fn try_build() {
+        assert!(matches!(
+            Builder::new(points(), 10).ref_points(0).try_build(),
+            Err(BuildError::NoRefPoints)
+        ));
+        assert!(matches!(
+            Builder::new(points(), 10)
+                .mean_distance(f64::INFINITY)
+                .try_build(),
+            Err(BuildError::NonFiniteRadius(_))
+        ));
+        assert!(matches!(
+            Builder::new(points(), 10).per_point_radii(0).try_build(),
+            Err(BuildError::NoRings)
+        ));
+        assert!(matches!(
+            Builder::new(points(), 10).calibrate(0, 0).try_build(),
+            Err(BuildError::EmptySample)
+        ));
+        assert!(matches!(
+            Builder::new(points(), 10)
+                .calibrate(0, 0)
+                .per_point_radii(3)
+                .try_build(),
+            Err(BuildError::EmptySample)
+        ));
+
+        let mut points = points();
+        points[5] = Euclidean::new([f64::NAN, 0.0]);
+        assert!(matches!(
+            Builder::new(points.clone(), 10)
+                .calibrate(1000, 0)
+                .try_build(),
+            Err(BuildError::NanDistance)
+        ));
+        assert!(matches!(
+            Builder::new(points, 10).try_build(),
+            Err(BuildError::NanDistance)
+        ));
+    }
+}
@@ -0,0 +1,345 @@
+use bitvec_simd::BitVec;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Run-length coding of a column of partitioning data: the positions at which its bits change value, starting from `false`.
+///
+/// Columns which are almost all ones or almost all zeros, such as balls at the outermost radii, have very few runs.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) struct Runs {
+    len: usize,
+    toggles: Vec<u32>,
+}
+
+impl Runs {
+    /// Encode a column of `len` bits, or return `None` if the encoding would not be smaller than storing the bits densely.
+    pub(crate) fn encode(len: usize, bits: impl IntoIterator<Item = bool>) -> Option<Self> {
+        if u32::try_from(len).is_err() {
+            return None;
+        }
+
+        let mut toggles = vec![];
+        let mut prev = false;
+        for (idx, bit) in bits.into_iter().take(len).enumerate() {
+            if bit != prev {
+                toggles.push(idx as u32);
+                prev = bit;
+            }
+            if toggles.len() * 4 >= dense_bytes(len) {
+                return None;
+            }
+        }
+
+        Some(Self { len, toggles })
+    }
+
+    /// A column of `len` bits which are all set.
+    pub(crate) fn full(len: usize) -> Self {
+        let toggles = if len > 0 { vec![0] } else { vec![] };
+        Self { len, toggles }
+    }
+
+    /// Decode runs of a column of `len` bits from little-endian `u32`s, or return `None` if they are malformed.
+    #[cfg(feature = "disk")]
+    pub(crate) fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        let chunks = bytes.chunks_exact(4);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+
+        let toggles = chunks
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        let runs = Self { len, toggles };
+
+        runs.is_valid().then_some(runs)
+    }
+
+    /// Whether the toggles are strictly increasing and within the column, as they are when encoded.
+    ///
+    /// Runs which are not valid make decoding them panic.
+    #[cfg(feature = "serde")]
+    pub(crate) fn is_valid(&self) -> bool {
+        self.toggles.windows(2).all(|w| w[0] < w[1])
+            && self
+                .toggles
+                .iter()
+                .all(|&toggle| (toggle as usize) < self.len)
+    }
+
+    /// Encode the runs as little-endian `u32`s.
+    #[cfg(feature = "disk")]
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.toggles.iter().flat_map(|t| t.to_le_bytes()).collect()
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Append a bit, or return `false` without appending it if the encoding would no longer be smaller than storing the bits densely.
+    fn push(&mut self, bit: bool) -> bool {
+        let toggle = bit != (self.toggles.len() % 2 == 1);
+        if u32::try_from(self.len + 1).is_err()
+            || (self.toggles.len() + toggle as usize) * 4 >= dense_bytes(self.len + 1)
+        {
+            return false;
+        }
+
+        if toggle {
+            self.toggles.push(self.len as u32);
+        }
+        self.len += 1;
+        true
+    }
+
+    /// Start and end of every run of ones.
+    pub(crate) fn ones(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.toggles.chunks(2).map(|run| {
+            let end = run.get(1).map_or(self.len, |&end| end as usize);
+            (run[0] as usize, end)
+        })
+    }
+
+    pub(crate) fn count_ones(&self) -> usize {
+        self.ones().map(|(start, end)| end - start).sum()
+    }
+
+    /// Runs of the bits set in both `self` and `other`.
+    pub(crate) fn and(&self, other: &Runs) -> Runs {
+        self.combine(other, |a, b| a && b)
+    }
+
+    /// Runs of the bits set in `self` but not in `other`.
+    pub(crate) fn and_not(&self, other: &Runs) -> Runs {
+        self.combine(other, |a, b| a && !b)
+    }
+
+    /// Combine two columns of the same length bit by bit, by merging their toggles. `op(false, false)` must be `false`.
+    fn combine(&self, other: &Runs, op: impl Fn(bool, bool) -> bool) -> Runs {
+        debug_assert_eq!(self.len, other.len);
+        let (mut lhs, mut rhs) = (
+            self.toggles.iter().peekable(),
+            other.toggles.iter().peekable(),
+        );
+        let (mut a, mut b, mut prev) = (false, false, false);
+        let mut toggles = vec![];
+        while let Some(&pos) = match (lhs.peek(), rhs.peek()) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        } {
+            a ^= lhs.next_if_eq(&pos).is_some();
+            b ^= rhs.next_if_eq(&pos).is_some();
+            if op(a, b) != prev {
+                toggles.push(*pos);
+                prev = !prev;
+            }
+        }
+
+        Runs {
+            len: self.len,
+            toggles,
+        }
+    }
+
+    /// The column as a dense bitset.
+    pub(crate) fn dense(&self) -> BitVec {
+        BitVec::from_slice_copy(&self.words(), self.len)
+    }
+
+    /// The column as dense `u64` words, least significant bit first. See [`dense_words`].
+    pub(crate) fn words(&self) -> Vec<u64> {
+        let mut words = vec![0_u64; dense_words(self.len)];
+        for (start, end) in self.ones() {
+            let (first, last) = (start / 64, (end - 1) / 64);
+            for (idx, word) in words.iter_mut().enumerate().take(last + 1).skip(first) {
+                let lo = if idx == first { start % 64 } else { 0 };
+                let hi = if idx == last { (end - 1) % 64 + 1 } else { 64 };
+                *word |= (u64::MAX >> (64 - (hi - lo))) << lo;
+            }
+        }
+        words
+    }
+}
+
+/// Number of `u64` words needed to store a column of `len` bits densely.
+///
+/// Columns are padded to a whole number of 256-bit lanes, so that their words are laid out exactly like the storage of a
+/// [`BitVec`], with the first word holding the first 64 bits.
+pub(crate) fn dense_words(len: usize) -> usize {
+    len.div_ceil(256) * 4
+}
+
+/// Number of bytes needed to store a column of `len` bits densely.
+pub(crate) fn dense_bytes(len: usize) -> usize {
+    dense_words(len) * 8
+}
+
+/// Pack a column of `len` bits into [dense words](dense_words). Bits past the end of the column are zero.
+#[cfg(feature = "disk")]
+pub(crate) fn pack(len: usize, bits: impl IntoIterator<Item = bool>) -> Vec<u64> {
+    let mut words = vec![0_u64; dense_words(len)];
+    for (idx, bit) in bits.into_iter().take(len).enumerate() {
+        words[idx / 64] |= (bit as u64) << (idx % 64);
+    }
+    words
+}
+
+/// The bits of dense words, least significant bit first.
+#[cfg(feature = "disk")]
+pub(crate) fn unpack(words: &[u64]) -> impl Iterator<Item = bool> + '_ {
+    words
+        .iter()
+        .flat_map(|word| (0..64).map(move |bit| (word >> bit) & 1 == 1))
+}
+
+/// Partitioning data for one zone, stored densely or as [`Runs`], whichever is smaller.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) enum Column {
+    Dense(BitVec),
+    Runs(Runs),
+}
+
+impl Column {
+    pub(crate) fn new(bits: Vec<bool>) -> Self {
+        match Runs::encode(bits.len(), bits.iter().copied()) {
+            Some(runs) => Column::Runs(runs),
+            None => Column::Dense(BitVec::from_bool_iterator(bits.into_iter())),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Column::Dense(bv) => bv.len(),
+            Column::Runs(runs) => runs.len(),
+        }
+    }
+
+    /// Whether the column can be decoded. See [`Runs::is_valid`].
+    #[cfg(feature = "serde")]
+    pub(crate) fn is_valid(&self) -> bool {
+        match self {
+            Column::Dense(_) => true,
+            Column::Runs(runs) => runs.is_valid(),
+        }
+    }
+
+    pub(crate) fn count_ones(&self) -> usize {
+        match self {
+            Column::Dense(bv) => bv.count_ones(),
+            Column::Runs(runs) => runs.count_ones(),
+        }
+    }
+
+    /// Append `bits` to the column. Runs are switched to a dense bitset once they are no longer smaller.
+    pub(crate) fn extend(&mut self, bits: impl IntoIterator<Item = bool>) {
+        let mut bits = bits.into_iter();
+        let mut rejected = None;
+        if let Column::Runs(runs) = self {
+            rejected = bits.by_ref().find(|&bit| !runs.push(bit));
+            if rejected.is_none() {
+                return;
+            }
+            *self = Column::Dense(runs.dense());
+        }
+
+        if let Column::Dense(bv) = self {
+            let bits = rejected.into_iter().chain(bits).collect::<Vec<_>>();
+            let start = bv.len();
+            bv.resize(start + bits.len(), false);
+            for (idx, bit) in bits.into_iter().enumerate() {
+                if bit {
+                    bv.set(start + idx, true);
+                }
+            }
+        }
+    }
+
+    /// The column as a dense bitset, decoding it if necessary.
+    pub(crate) fn dense(&self) -> Cow<'_, BitVec> {
+        match self {
+            Column::Dense(bv) => Cow::Borrowed(bv),
+            Column::Runs(runs) => Cow::Owned(runs.dense()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_round_trip() {
+        let bits = (0..1000)
+            .map(|i| (3..70).contains(&i) || (128..192).contains(&i) || i >= 990)
+            .collect::<Vec<_>>();
+        let runs = Runs::encode(bits.len(), bits.iter().copied()).unwrap();
+
+        assert_eq!(runs.toggles, [3, 70, 128, 192, 990]);
+        assert_eq!(runs.count_ones(), bits.iter().filter(|&&b| b).count());
+        assert_eq!(
+            BitVec::from_slice_copy(&runs.words(), bits.len()).into_bools(),
+            bits
+        );
+
+        // Alternating bits are smaller stored densely.
+        let bits = (0..1000).map(|i| i % 2 == 0).collect::<Vec<_>>();
+        assert_eq!(Runs::encode(bits.len(), bits.iter().copied()), None);
+        assert!(matches!(Column::new(bits), Column::Dense(_)));
+    }
+
+    #[test]
+    fn runs_and_not() {
+        let a = (0..1000).map(|i| (100..600).contains(&i) || i >= 900);
+        let b = (0..1000).map(|i| (50..150).contains(&i) || (400..950).contains(&i));
+        let (a_runs, b_runs) = (
+            Runs::encode(1000, a.clone()).unwrap(),
+            Runs::encode(1000, b.clone()).unwrap(),
+        );
+
+        let and = a.clone().zip(b.clone()).map(|(a, b)| a && b);
+        assert_eq!(a_runs.and(&b_runs), Runs::encode(1000, and).unwrap());
+        let and_not = a.zip(b).map(|(a, b)| a && !b);
+        assert_eq!(
+            a_runs.and_not(&b_runs),
+            Runs::encode(1000, and_not).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn runs_valid() {
+        let runs = |toggles: &[u32]| Runs {
+            len: 100,
+            toggles: toggles.to_vec(),
+        };
+
+        assert!(runs(&[]).is_valid());
+        assert!(runs(&[3, 50, 99]).is_valid());
+        assert!(!runs(&[50, 3]).is_valid());
+        assert!(!runs(&[3, 3]).is_valid());
+        assert!(!runs(&[3, 100]).is_valid());
+    }
+
+    #[test]
+    fn column_extend() {
+        let bits = (0..1000).map(|i| i >= 600).collect::<Vec<_>>();
+        let mut column = Column::new(bits[..300].to_vec());
+        column.extend(bits[300..].iter().copied());
+        assert_eq!(column, Column::new(bits.clone()));
+
+        // Runs which grow too long are switched to a dense bitset.
+        let more = (0..1000).map(|i| i % 2 == 0).collect::<Vec<_>>();
+        column.extend(more.iter().copied());
+        assert!(matches!(column, Column::Dense(_)));
+        assert_eq!(
+            column.dense().into_owned().into_bools(),
+            [bits, more].concat()
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Single-file container holding the partitioning data of a [`Disk`](crate::Disk).
+//!
+//! The file starts with the same header as [saved](crate::Sequential::save) data structures, followed by the offset of the table as a
+//! little-endian `u64`. Segments of partitioning data follow, each aligned to the alignment the container was created with, and the table
+//! comes last: it holds the metadata of the data structure along with the offset, length, encoding and CRC-32 checksum of every segment.
+//! Placing the table last means segments can be written as they are computed.
+//!
+//! A container can also be appended to: new segments and a new table are written after the old table, which is replaced once the
+//! offset in the header is updated. Segments which are not kept in the new table are left in place as unused space.
+
+use crate::persist::{self, Kind, PersistError};
+use crate::DiskError;
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Location, encoding and checksum of a segment within a container.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Segment {
+    offset: u64,
+    len: u64,
+    compressed: bool,
+    checksum: u32,
+}
+
+/// Offset of the first segment.
+const DATA_OFFSET: u64 = persist::HEADER_LEN as u64 + 8;
+/// Smallest alignment of segments, which is that of a 256-bit SIMD lane.
+pub(crate) const ALIGN: u64 = 32;
+/// Size of a page of memory, which large segments are aligned to.
+pub(crate) const PAGE: u64 = 4096;
+
+/// Writes a container one segment at a time.
+pub(crate) struct Writer {
+    writer: BufWriter<File>,
+    offset: u64,
+    align: u64,
+    segments: Vec<Segment>,
+}
+
+impl Writer {
+    /// Create a new container at `path`, whose segments start on `align`-byte boundaries.
+    pub(crate) fn create(path: &Path, align: u64) -> Result<Self, DiskError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        persist::write_header(&mut writer, Kind::Disk)?;
+        // The table offset is filled in by `finish`.
+        writer.write_all(&0_u64.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            offset: DATA_OFFSET,
+            align,
+            segments: vec![],
+        })
+    }
+
+    /// Reopen the container at `path` to append segments after its end, starting on `align`-byte boundaries.
+    ///
+    /// The new table only holds the segments which are [kept](Writer::keep) or pushed. The container reads as it did before until
+    /// [`finish`](Writer::finish) updates the offset of the table.
+    pub(crate) fn append(path: &Path, align: u64) -> Result<Self, DiskError> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            offset,
+            align,
+            segments: vec![],
+        })
+    }
+
+    /// Add segment `idx` of `reader`, which must have been opened on the container being appended to, without copying it.
+    pub(crate) fn keep(&mut self, reader: &Reader, idx: usize) {
+        self.segments.push(reader.segments[idx]);
+    }
+
+    /// Append a segment, padding the file so that it starts on an aligned boundary.
+    /// `compressed` records which encoding the segment uses.
+    pub(crate) fn push(&mut self, segment: &[u8], compressed: bool) -> Result<(), DiskError> {
+        let padding = self.offset.next_multiple_of(self.align) - self.offset;
+        self.writer.write_all(&vec![0; padding as usize])?;
+        self.offset += padding;
+
+        self.writer.write_all(segment)?;
+        self.segments.push(Segment {
+            offset: self.offset,
+            len: segment.len() as u64,
+            compressed,
+            checksum: persist::crc32(segment),
+        });
+        self.offset += segment.len() as u64;
+        Ok(())
+    }
+
+    /// Write the table, made up of `metadata` and the segments pushed so far, and close the container.
+    pub(crate) fn finish<M>(mut self, metadata: &M) -> Result<(), DiskError>
+    where
+        M: Serialize,
+    {
+        bincode::serialize_into(&mut self.writer, &(metadata, &self.segments))?;
+        self.writer
+            .seek(SeekFrom::Start(persist::HEADER_LEN as u64))?;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A container mapped into memory, read-only.
+pub(crate) struct Reader {
+    mmap: memmap2::Mmap,
+    table: u64,
+    segments: Vec<Segment>,
+}
+
+impl Reader {
+    /// Open the container at `path`, returning it along with its metadata.
+    ///
+    /// Returns [`PersistError::Corrupt`] if the table cannot be read, or if it refers to segments outside of the file. The contents
+    /// of segments are not checked.
+    pub(crate) fn open<M>(path: &Path) -> Result<(Self, M), DiskError>
+    where
+        M: DeserializeOwned,
+    {
+        let mmap = unsafe { memmap2::Mmap::map(&File::open(path)?)? };
+        let mut header = &mmap[..];
+        persist::read_header(&mut header, Kind::Disk)?;
+
+        let table = header
+            .get(..8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+            .filter(|&offset| (DATA_OFFSET..=mmap.len() as u64).contains(&offset))
+            .ok_or(PersistError::Corrupt)?;
+        // The table cannot be larger than the rest of the file, which stops a corrupt length from causing a huge allocation.
+        let (metadata, segments): (M, Vec<Segment>) = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(mmap.len() as u64 - table)
+            .deserialize(&mmap[table as usize..])
+            .map_err(|_| PersistError::Corrupt)?;
+
+        if segments
+            .iter()
+            .any(|seg| seg.offset < DATA_OFFSET || seg.offset.saturating_add(seg.len) > table)
+        {
+            return Err(PersistError::Corrupt.into());
+        }
+
+        Ok((
+            Self {
+                mmap,
+                table,
+                segments,
+            },
+            metadata,
+        ))
+    }
+
+    /// Number of segments in the container.
+    pub(crate) fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// The bytes of segment `idx`.
+    pub(crate) fn segment(&self, idx: usize) -> &[u8] {
+        let seg = self.segments[idx];
+        &self.mmap[seg.offset as usize..(seg.offset + seg.len) as usize]
+    }
+
+    /// Whether more than half of the file is unused space left behind by appending, assuming segments aligned to `align` bytes.
+    pub(crate) fn is_sparse(&self, align: u64) -> bool {
+        let used = DATA_OFFSET
+            + self
+                .segments
+                .iter()
+                .map(|seg| seg.len.next_multiple_of(align))
+                .sum::<u64>()
+            + (self.mmap.len() as u64 - self.table);
+        self.mmap.len() as u64 > 2 * used
+    }
+
+    /// Whether segment `idx` was pushed as compressed.
+    pub(crate) fn is_compressed(&self, idx: usize) -> bool {
+        self.segments[idx].compressed
+    }
+
+    /// The bytes of segment `idx` as little-endian `u64` words.
+    ///
+    /// On little-endian platforms, aligned segments are viewed in place. Otherwise, the words are copied out.
+    pub(crate) fn words(&self, idx: usize) -> Cow<'_, [u64]> {
+        let bytes = self.segment(idx);
+
+        #[cfg(target_endian = "little")]
+        {
+            // SAFETY: every bit pattern is a valid `u64`, and `align_to` only returns words which are correctly aligned.
+            let (prefix, words, suffix) = unsafe { bytes.align_to::<u64>() };
+            if prefix.is_empty() && suffix.is_empty() {
+                return Cow::Borrowed(words);
+            }
+        }
+
+        Cow::Owned(
+            bytes
+                .chunks_exact(8)
+                .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    /// Indices of the segments which do not match their checksum.
+    pub(crate) fn corrupt(&self) -> Vec<usize> {
+        (0..self.segments.len())
+            .filter(|&idx| persist::crc32(self.segment(idx)) != self.segments[idx].checksum)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_length_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = &dir.path().join("index");
+        let writer = Writer::create(path, ALIGN).unwrap();
+        writer.finish(&vec![0_u8; 16]).unwrap();
+        assert_eq!(Reader::open::<Vec<u8>>(path).unwrap().1, [0; 16]);
+
+        // The length of the metadata is the first thing in the table, which comes straight after the table offset.
+        let mut bytes = std::fs::read(path).unwrap();
+        let table = DATA_OFFSET as usize;
+        bytes[table..table + 8].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        std::fs::write(path, bytes).unwrap();
+        assert!(matches!(
+            Reader::open::<Vec<u8>>(path),
+            Err(DiskError::Manifest(PersistError::Corrupt))
+        ));
+    }
+}
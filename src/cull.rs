@@ -0,0 +1,239 @@
+use bitvec_simd::BitVec;
+use itertools::Itertools;
+use thiserror::Error;
+
+/// Removal of exclusion zones with low exclusion power.
+///
+/// Not every exclusion zone is useful: a zone which contains almost all (or almost none) of the dataset rarely excludes anything,
+/// and two zones which partition the dataset in almost the same way are redundant. Culling such zones reduces the memory used by
+/// the data structure and the amount of partitioning data scanned per query, while usually keeping the number of candidates low.
+pub trait Cullable<T> {
+    /// Error type for culling.
+    type Error: std::error::Error;
+
+    /// Cull exclusion zones with low exclusion power.
+    /// This function will compare all zones in in the data structure with one another and calculate their [Hamming distance](https://en.wikipedia.org/wiki/Hamming_distance).
+    /// If a zone's similarity ratio is above the given `threshold`, it is marked for removal.
+    fn cull_by_similarity(&mut self, threshold: f64) -> Result<(), Self::Error>;
+
+    /// Cull exclusion zones with low exclusion power.
+    /// This function measures the exclusion power of a zone by counting the ratio of points that are in/out to the dataset.
+    /// If either ratio is above the `threshold` given, it is marked for removal.
+    fn cull_by_popcnt(&mut self, threshold: f64) -> Result<(), Self::Error>;
+
+    /// Keep the `budget` exclusion zones which prune best for a sample workload, and cull the rest.
+    ///
+    /// Every query in `sample_queries` is classified against every zone using `threshold`, and the number of candidates each
+    /// zone removes from each query is measured. Zones are then chosen greedily: at each step, the zone which removes the most
+    /// candidates that are not already removed by the zones chosen so far is kept. This favours zones which prune well *together*,
+    /// rather than zones which prune well individually but remove the same points.
+    ///
+    /// `sample_queries` should be representative of the queries the data structure will be used for, and `threshold` should be
+    /// a typical query radius. If `budget` is greater than the number of zones, no zones are culled.
+    ///
+    /// # Errors
+    /// Returns [`CullError::NoZonesKept`] (or the data structure's equivalent) if `budget` is zero.
+    fn select_zones_for_workload(
+        &mut self,
+        sample_queries: &[T],
+        threshold: f64,
+        budget: usize,
+    ) -> Result<(), Self::Error>;
+
+    /// Keep exactly `n_zones` exclusion zones, and cull the rest.
+    ///
+    /// Unlike [`cull_by_popcnt`](crate::Cullable::cull_by_popcnt) and [`cull_by_similarity`](crate::Cullable::cull_by_similarity),
+    /// the number of zones left is known in advance. Zones are chosen greedily by a combined score: a zone's *balance* is highest when it
+    /// contains half of the dataset, and is multiplied by how *uncorrelated* it is with the most similar zone chosen so far.
+    /// Zones which are the complement of a chosen zone count as fully correlated with it.
+    ///
+    /// If `n_zones` is greater than the number of zones, no zones are culled.
+    ///
+    /// # Errors
+    /// Returns [`CullError::NoZonesKept`] (or the data structure's equivalent) if `n_zones` is zero.
+    fn cull_to(&mut self, n_zones: usize) -> Result<(), Self::Error>;
+}
+
+/// Which of `zones` zones to keep when culling by similarity, given the number of points in the dataset and
+/// a function which returns the Hamming distance between two zones.
+pub(crate) fn keep_by_similarity(
+    zones: usize,
+    len: usize,
+    threshold: f64,
+    hamming: impl Fn(usize, usize) -> usize,
+) -> Vec<bool> {
+    let mut keep = vec![true; zones];
+    for indices in (0..zones).combinations(2) {
+        let i = indices[0];
+        let j = indices[1];
+
+        if 1.0 - ratio(hamming(i, j), len) > threshold {
+            keep[j] = false;
+        }
+    }
+    keep
+}
+
+/// Which zones to keep when culling by popcount, given the number of points in the dataset and the popcount of each zone.
+pub(crate) fn keep_by_popcnt(popcnt: &[usize], len: usize, threshold: f64) -> Vec<bool> {
+    popcnt
+        .iter()
+        .map(|&cnt| ratio(cnt, len) <= threshold && ratio(len - cnt, len) <= threshold)
+        .collect()
+}
+
+/// Which zones to keep when selecting zones for a workload.
+///
+/// `columns` holds the partitioning data of each zone over the whole dataset, and `decisions` holds the zones
+/// each sample query must be in and out of.
+pub(crate) fn keep_for_workload(
+    columns: &[BitVec],
+    decisions: &[(Vec<usize>, Vec<usize>)],
+    len: usize,
+    budget: usize,
+) -> Result<Vec<bool>, CullError> {
+    if budget == 0 {
+        return Err(CullError::NoZonesKept);
+    }
+
+    let zones = columns.len();
+    if budget >= zones {
+        return Ok(vec![true; zones]);
+    }
+
+    // For each query, whether it must be in (`Some(true)`) or out of (`Some(false)`) each zone.
+    let decisions = decisions
+        .iter()
+        .map(|(ins, outs)| {
+            let mut decision = vec![None; zones];
+            ins.iter().for_each(|&zone| decision[zone] = Some(true));
+            outs.iter().for_each(|&zone| decision[zone] = Some(false));
+            decision
+        })
+        .collect::<Vec<_>>();
+
+    // Number of candidates a zone removes, summed over all queries.
+    let removed = |candidates: &[BitVec], zone: usize| {
+        candidates
+            .iter()
+            .zip(&decisions)
+            .map(|(cands, decision)| match decision[zone] {
+                Some(true) => cands.difference_cloned(&columns[zone]).count_ones(),
+                Some(false) => cands.and_cloned(&columns[zone]).count_ones(),
+                None => 0,
+            })
+            .sum::<usize>()
+    };
+
+    let mut candidates = vec![BitVec::ones(len); decisions.len()];
+    // Zones which remove nothing on top of the ones already chosen are ranked by how much they remove on their own.
+    let individual = (0..zones)
+        .map(|zone| removed(&candidates, zone))
+        .collect::<Vec<_>>();
+
+    let mut keep = vec![false; zones];
+    for _ in 0..budget {
+        let (best, _) = (0..zones)
+            .filter(|&zone| !keep[zone])
+            .map(|zone| (zone, (removed(&candidates, zone), individual[zone])))
+            .max_by(|(a, x), (b, y)| x.cmp(y).then(b.cmp(a)))
+            .unwrap();
+
+        keep[best] = true;
+        for (cands, decision) in candidates.iter_mut().zip(&decisions) {
+            match decision[best] {
+                Some(true) => cands.and_inplace(&columns[best]),
+                Some(false) => *cands = cands.difference_cloned(&columns[best]),
+                None => {}
+            }
+        }
+    }
+    Ok(keep)
+}
+
+/// Which `n` zones to keep when culling to a fixed number of zones, given the number of points in the dataset,
+/// the popcount of each zone and a function which returns the Hamming distance between two zones.
+pub(crate) fn keep_n(
+    popcnt: &[usize],
+    len: usize,
+    n: usize,
+    hamming: impl Fn(usize, usize) -> usize,
+) -> Result<Vec<bool>, CullError> {
+    if n == 0 {
+        return Err(CullError::NoZonesKept);
+    }
+
+    let zones = popcnt.len();
+    if n >= zones {
+        return Ok(vec![true; zones]);
+    }
+
+    let balance = popcnt
+        .iter()
+        .map(|&cnt| 1.0 - (2.0 * ratio(cnt, len) - 1.0).abs())
+        .collect::<Vec<_>>();
+    // Highest correlation of each zone with any of the zones chosen so far.
+    let mut correlation = vec![0.0_f64; zones];
+
+    let mut keep = vec![false; zones];
+    for _ in 0..n {
+        let (best, _) = (0..zones)
+            .filter(|&zone| !keep[zone])
+            .map(|zone| (zone, balance[zone] * (1.0 - correlation[zone])))
+            .max_by(|(a, x), (b, y)| x.total_cmp(y).then(b.cmp(a)))
+            .unwrap();
+
+        keep[best] = true;
+        for zone in (0..zones).filter(|&zone| !keep[zone]) {
+            let similarity = 1.0 - ratio(hamming(best, zone), len);
+            correlation[zone] = correlation[zone].max((2.0 * similarity - 1.0).abs());
+        }
+    }
+    Ok(keep)
+}
+
+/// Retain the elements of `items` for which `keep` is `true`.
+pub(crate) fn retain<I>(items: &mut Vec<I>, keep: &[bool]) {
+    let mut iter = keep.iter();
+    items.retain(|_| *iter.next().unwrap());
+}
+
+fn ratio(ones: usize, len: usize) -> f64 {
+    ones as f64 / len as f64
+}
+
+/// Errors that can be encountered while culling exclusion zones.
+#[derive(Debug, Error)]
+pub enum CullError {
+    /// [`cull_to`](crate::Cullable::cull_to) or [`select_zones_for_workload`](crate::Cullable::select_zones_for_workload)
+    /// was asked to keep zero exclusion zones.
+    #[error("at least one exclusion zone must be kept")]
+    NoZonesKept,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_n_balance_and_correlation() {
+        // Zone 1 is the complement of zone 0, zone 2 is independent of it, and zone 3 is very unbalanced.
+        let popcnt = [50, 50, 40, 5];
+        let hamming = |i: usize, j: usize| match (i.min(j), i.max(j)) {
+            (0, 1) => 100,
+            (1, 2) | (0, 2) => 50,
+            _ => 45,
+        };
+
+        assert_eq!(
+            keep_n(&popcnt, 100, 1, hamming).unwrap(),
+            [true, false, false, false]
+        );
+        assert_eq!(
+            keep_n(&popcnt, 100, 2, hamming).unwrap(),
+            [true, false, true, false]
+        );
+        assert_eq!(keep_n(&popcnt, 100, 4, hamming).unwrap(), [true; 4]);
+        assert_eq!(keep_n(&popcnt, 100, 5, hamming).unwrap(), [true; 4]);
+    }
+}
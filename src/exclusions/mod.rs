@@ -4,10 +4,19 @@
 //!
 //! # Default implementation
 //! The reference implementation uses both ball and sheet implementations, as described in the paper.
+//! In [supermetric](crate::metric::Supermetric) spaces, sheets can use four-point (Hilbert) exclusion instead of three-point exclusion.
 //!
-//! While the Exclusion traits are not sealed, it is currently not possible to use custom exclusion zones in BitPart.
+//! # Custom exclusion zones
+//! The [`Exclusion`] and [`ExclusionSync`] traits are not sealed. Custom exclusion zones can be added to a data structure with
+//! [`with_exclusions`](crate::Builder::with_exclusions) or [`with_exclusions_sync`](crate::Builder::with_exclusions_sync).
+//!
+//! For a zone to be valid, it must never report that a point [must be in](Exclusion::must_be_in) the zone if there is a point
+//! within `threshold` of it which is not [in](Exclusion::is_in) the zone, and vice versa for [must be out](Exclusion::must_be_out).
 
-use crate::metric::Metric;
+use crate::metric::{Metric, Supermetric};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
 #[cfg(feature = "par")]
 /// Marker trait for exclusions that are also `Send` and `Sync`.
@@ -30,7 +39,36 @@ where
     fn must_be_out(&self, point: &T, threshold: f64) -> bool;
 }
 
-pub(crate) struct BallExclusion<T> {
+impl<T, E> Exclusion<T> for Arc<E>
+where
+    T: Metric,
+    E: Exclusion<T> + ?Sized,
+{
+    fn is_in(&self, point: &T) -> bool {
+        self.as_ref().is_in(point)
+    }
+
+    fn must_be_in(&self, point: &T, threshold: f64) -> bool {
+        self.as_ref().must_be_in(point, threshold)
+    }
+
+    fn must_be_out(&self, point: &T, threshold: f64) -> bool {
+        self.as_ref().must_be_out(point, threshold)
+    }
+}
+
+#[cfg(feature = "par")]
+impl<T, E> ExclusionSync<T> for Arc<E>
+where
+    T: Metric + Send + Sync,
+    E: ExclusionSync<T> + ?Sized,
+{
+}
+
+/// Ball exclusion zone.
+///
+/// Points are in the zone if their distance to `point` is less than `radius`.
+pub struct BallExclusion<T> {
     pub(crate) point: T,
     pub(crate) radius: f64,
 }
@@ -39,7 +77,8 @@ impl<T> BallExclusion<T>
 where
     T: Metric,
 {
-    pub(crate) fn new(point: T, radius: f64) -> Self {
+    /// Creates a new `BallExclusion` centred on `point`.
+    pub fn new(point: T, radius: f64) -> Self {
         Self { point, radius }
     }
 }
@@ -64,7 +103,11 @@ where
 #[cfg(feature = "par")]
 impl<T> ExclusionSync<T> for BallExclusion<T> where T: Metric + Send + Sync {}
 
-pub(crate) struct SheetExclusion<T> {
+/// Sheet exclusion zone, using the three-point property of metric spaces.
+///
+/// Points are in the zone if `d(a, point) - d(b, point)` is less than `offset`. With an offset of zero, this means that points are in
+/// the zone if they are closer to `a` than to `b`.
+pub struct SheetExclusion<T> {
     a: T,
     b: T,
     offset: f64,
@@ -74,7 +117,8 @@ impl<T> SheetExclusion<T>
 where
     T: Metric,
 {
-    pub(crate) fn new(a: T, b: T, offset: f64) -> Self {
+    /// Creates a new `SheetExclusion` between `a` and `b`.
+    pub fn new(a: T, b: T, offset: f64) -> Self {
         Self { a, b, offset }
     }
 }
@@ -98,3 +142,214 @@ where
 
 #[cfg(feature = "par")]
 impl<T> ExclusionSync<T> for SheetExclusion<T> where T: Metric + Send + Sync {}
+
+/// Sheet exclusion using the four-point property of supermetric spaces (also known as Hilbert exclusion).
+///
+/// Points are split by the hyperplane perpendicular to the line between `a` and `b`. Each point is projected onto that line using only
+/// its distances to `a` and `b`. The projection is measured from the midpoint, so the plane lies at `offset` from the perpendicular bisector.
+/// Points on the same side of the plane as `a` are in the zone.
+pub struct HilbertExclusion<T> {
+    a: T,
+    b: T,
+    offset: f64,
+    distance: f64,
+}
+
+impl<T> HilbertExclusion<T>
+where
+    T: Supermetric,
+{
+    /// Creates a new `HilbertExclusion` between `a` and `b`.
+    ///
+    /// # Panics
+    /// This function will panic if `a` and `b` are not a positive distance apart, as points cannot be projected onto the line
+    /// between them.
+    pub fn new(a: T, b: T, offset: f64) -> Self {
+        let exclusion = Self::new_unchecked(a, b, offset);
+        assert!(
+            exclusion.distance > 0.0,
+            "reference points of a HilbertExclusion must be a positive distance apart"
+        );
+        exclusion
+    }
+}
+
+impl<T> HilbertExclusion<T>
+where
+    T: Metric,
+{
+    /// Callers must ensure that `T` is a [supermetric](crate::metric::Supermetric), otherwise results will be incorrect.
+    pub(crate) fn new_unchecked(a: T, b: T, offset: f64) -> Self {
+        let distance = a.distance(&b);
+        Self {
+            a,
+            b,
+            offset,
+            distance,
+        }
+    }
+
+    /// Signed distance of `point`'s projection from the plane. Negative values are on the side of `a`.
+    fn projection(&self, point: &T) -> f64 {
+        (self.a.distance(point).powi(2) - self.b.distance(point).powi(2)) / (2.0 * self.distance)
+            - self.offset
+    }
+}
+
+impl<T> Exclusion<T> for HilbertExclusion<T>
+where
+    T: Metric,
+{
+    fn is_in(&self, point: &T) -> bool {
+        self.projection(point) < 0.0
+    }
+
+    fn must_be_in(&self, point: &T, threshold: f64) -> bool {
+        self.projection(point) < -threshold
+    }
+
+    fn must_be_out(&self, point: &T, threshold: f64) -> bool {
+        self.projection(point) >= threshold
+    }
+}
+
+#[cfg(feature = "par")]
+impl<T> ExclusionSync<T> for HilbertExclusion<T> where T: Metric + Send + Sync {}
+
+/// Definition of an exclusion zone created by a [`Builder`](crate::Builder), in terms of indices into its reference points.
+///
+/// Unlike the zones themselves, definitions can be stored and compared, and the zones can be recreated from them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) enum ZoneDef {
+    Ball {
+        point: usize,
+        radius: f64,
+    },
+    Sheet {
+        a: usize,
+        b: usize,
+        offset: f64,
+    },
+    Hilbert {
+        a: usize,
+        b: usize,
+        offset: f64,
+    },
+    /// A zone added with [`with_exclusions`](crate::Builder::with_exclusions) or
+    /// [`with_exclusions_sync`](crate::Builder::with_exclusions_sync), which cannot be recreated.
+    Custom,
+}
+
+impl ZoneDef {
+    /// Recreate the zone from `ref_points`, unless it is a custom zone.
+    pub(crate) fn exclusion<T>(&self, ref_points: &[T]) -> Option<Builtin<T>>
+    where
+        T: Metric,
+    {
+        match *self {
+            ZoneDef::Ball { point, radius } => Some(Builtin::Ball(BallExclusion::new(
+                ref_points[point].clone(),
+                radius,
+            ))),
+            ZoneDef::Sheet { a, b, offset } => Some(Builtin::Sheet(SheetExclusion::new(
+                ref_points[a].clone(),
+                ref_points[b].clone(),
+                offset,
+            ))),
+            ZoneDef::Hilbert { a, b, offset } => {
+                Some(Builtin::Hilbert(HilbertExclusion::new_unchecked(
+                    ref_points[a].clone(),
+                    ref_points[b].clone(),
+                    offset,
+                )))
+            }
+            ZoneDef::Custom => None,
+        }
+    }
+
+    /// Whether the zone is built-in, and refers only to reference points in `0..ref_points`.
+    #[cfg(feature = "serde")]
+    pub(crate) fn is_valid(&self, ref_points: usize) -> bool {
+        match *self {
+            ZoneDef::Ball { point, .. } => point < ref_points,
+            ZoneDef::Sheet { a, b, .. } | ZoneDef::Hilbert { a, b, .. } => {
+                a < ref_points && b < ref_points
+            }
+            ZoneDef::Custom => false,
+        }
+    }
+}
+
+/// One of the exclusion zones a [`ZoneDef`] can describe.
+pub(crate) enum Builtin<T> {
+    Ball(BallExclusion<T>),
+    Sheet(SheetExclusion<T>),
+    Hilbert(HilbertExclusion<T>),
+}
+
+impl<T> Exclusion<T> for Builtin<T>
+where
+    T: Metric,
+{
+    fn is_in(&self, point: &T) -> bool {
+        match self {
+            Builtin::Ball(ez) => ez.is_in(point),
+            Builtin::Sheet(ez) => ez.is_in(point),
+            Builtin::Hilbert(ez) => ez.is_in(point),
+        }
+    }
+
+    fn must_be_in(&self, point: &T, threshold: f64) -> bool {
+        match self {
+            Builtin::Ball(ez) => ez.must_be_in(point, threshold),
+            Builtin::Sheet(ez) => ez.must_be_in(point, threshold),
+            Builtin::Hilbert(ez) => ez.must_be_in(point, threshold),
+        }
+    }
+
+    fn must_be_out(&self, point: &T, threshold: f64) -> bool {
+        match self {
+            Builtin::Ball(ez) => ez.must_be_out(point, threshold),
+            Builtin::Sheet(ez) => ez.must_be_out(point, threshold),
+            Builtin::Hilbert(ez) => ez.must_be_out(point, threshold),
+        }
+    }
+}
+
+#[cfg(feature = "par")]
+impl<T> ExclusionSync<T> for Builtin<T> where T: Metric + Send + Sync {}
+
+#[cfg(test)]
+mod tests {
+    use crate::metric::Euclidean;
+
+    use super::*;
+
+    #[test]
+    fn hilbert_2d() {
+        let a = Euclidean::new([0.0, 0.0]);
+        let b = Euclidean::new([2.0, 0.0]);
+        let ez = HilbertExclusion::new(a, b, 0.5);
+
+        // The plane is at x = 1.5, and the projection is the exact distance to it.
+        let point = Euclidean::new([0.5, 3.0]);
+        assert!((ez.projection(&point) + 1.0).abs() < 1e-9);
+        assert!(ez.is_in(&point));
+        assert!(ez.must_be_in(&point, 0.9));
+        assert!(!ez.must_be_in(&point, 1.1));
+        assert!(!ez.must_be_out(&point, 0.0));
+
+        let point = Euclidean::new([2.0, -1.0]);
+        assert!(!ez.is_in(&point));
+        assert!(ez.must_be_out(&point, 0.4));
+        assert!(!ez.must_be_out(&point, 0.6));
+    }
+
+    #[test]
+    #[should_panic(expected = "positive distance apart")]
+    fn hilbert_coincident() {
+        let a = Euclidean::new([1.0, 1.0]);
+        HilbertExclusion::new(a.clone(), a, 0.0);
+    }
+}
@@ -33,13 +33,28 @@
 //! # Features
 //! - `rayon`: Enables parallelised BitPart
 //! - `disk`: Enables on-disk BitPart.
-//! - `serde`: Enables (de)serialization through [`serde`](serde)
+//! - `serde`: Enables (de)serialization through [`serde`](serde), and saving and loading [`Sequential`] and [`Parallel`] to and from files.
 
 #![deny(missing_docs)]
 
+use std::time::Duration;
+
 mod builder;
 pub use builder::*;
 
+#[cfg(feature = "par")]
+mod compress;
+
+mod cull;
+pub use cull::{CullError, Cullable};
+
+mod random;
+
+#[cfg(feature = "serde")]
+mod persist;
+#[cfg(feature = "serde")]
+pub use persist::PersistError;
+
 pub mod exclusions;
 pub mod metric;
 
@@ -56,6 +71,13 @@ mod on_disk;
 #[cfg(feature = "disk")]
 pub use on_disk::*;
 
+#[cfg(feature = "disk")]
+mod container;
+#[cfg(feature = "disk")]
+mod store;
+#[cfg(feature = "disk")]
+pub use store::FixedDimension;
+
 /// Trait for BitPart data structures.
 pub trait BitPart<T> {
     /// Corresponding error type for the data structure.
@@ -66,6 +88,42 @@ pub trait BitPart<T> {
     /// Returns a vector of points which fall within the specific radius, along with their distance from the query `point`.
     fn range_search(&self, point: T, threshold: f64) -> Result<Vec<(T, f64)>, Self::Error>;
 
+    /// Perform a range search, given a `point` and a radius `threshold` around it.
+    ///
+    /// Returns a vector of indices into the dataset for points which fall within the specific radius, along with their distance from
+    /// the query `point`. Unlike [`range_search`](crate::BitPart::range_search), no points are cloned.
+    fn range_search_indices(
+        &self,
+        point: T,
+        threshold: f64,
+    ) -> Result<Vec<(usize, f64)>, Self::Error>;
+
+    /// Perform a range search, given a `point` and a radius `threshold` around it.
+    ///
+    /// Returns a vector of references to points which fall within the specific radius, along with their distance from the query `point`.
+    /// Unlike [`range_search`](crate::BitPart::range_search), no points are cloned.
+    fn range_search_ref(&self, point: T, threshold: f64) -> Result<Vec<(&T, f64)>, Self::Error>;
+
+    /// Perform a range search, given a `point` and a radius `threshold` around it.
+    ///
+    /// Returns the same results as [`range_search`](crate::BitPart::range_search), along with [`QueryStats`] describing how the query was executed.
+    #[allow(clippy::type_complexity)]
+    fn range_search_with_stats(
+        &self,
+        point: T,
+        threshold: f64,
+    ) -> Result<(Vec<(T, f64)>, QueryStats), Self::Error>;
+
+    /// Perform a k-nearest-neighbour search, given a `point` and the number of neighbours `k` to find.
+    ///
+    /// Returns the `k` points closest to `point` (or the whole dataset, if it holds fewer than `k` points), along with their distance from
+    /// the query `point`. Results are sorted by distance, closest first.
+    ///
+    /// The search is exact. It is performed as a series of range searches over the same exclusion zones, starting from the
+    /// [`radius_increment`](crate::Builder::radius_increment) the data structure was built with and doubling the radius until
+    /// at least `k` points have been found.
+    fn knn_search(&self, point: T, k: usize) -> Result<Vec<(T, f64)>, Self::Error>;
+
     /// Returns the size of the dataset.
     fn len(&self) -> usize;
 
@@ -75,3 +133,64 @@ pub trait BitPart<T> {
     /// Returns the number of exclusion zones being used.
     fn zones(&self) -> usize;
 }
+
+/// Statistics describing how a single range search was executed.
+///
+/// These are useful for tuning the number of reference points and the radii of ball exclusions: ideally, most zones should be decided
+/// for a typical query, and the number of candidates should be close to the number of results.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryStats {
+    /// Number of exclusion zones the query [must be in](crate::exclusions::Exclusion::must_be_in).
+    pub must_be_in: usize,
+    /// Number of exclusion zones the query [must be out](crate::exclusions::Exclusion::must_be_out) of.
+    pub must_be_out: usize,
+    /// Number of exclusion zones which could not be used to filter points.
+    pub undecided: usize,
+    /// Number of candidate points left after filtering with partitioning data.
+    pub candidates: usize,
+    /// Number of distance calculations made while checking candidates.
+    ///
+    /// This does not include distance calculations made by exclusion zones themselves.
+    pub distance_calls: usize,
+    /// Time spent deciding which exclusion zones the query must be in or out of.
+    pub exclusion_time: Duration,
+    /// Time spent filtering points with partitioning data. For [`Disk`](crate::Disk), this includes loading columns from disk.
+    pub filter_time: Duration,
+    /// Time spent calculating distances to candidates.
+    pub verify_time: Duration,
+}
+
+/// Find the `k` nearest neighbours of a query by repeatedly widening a range search.
+///
+/// `search` performs a range search at the given radius and returns the indices and distances of the points found.
+/// Any point within the radius is guaranteed to be found, so once at least `k` points are returned the closest `k` of them are exact.
+pub(crate) fn expanding_knn<E>(
+    len: usize,
+    k: usize,
+    initial_radius: f64,
+    mut search: impl FnMut(f64) -> Result<Vec<(usize, f64)>, E>,
+) -> Result<Vec<(usize, f64)>, E> {
+    let k = k.min(len);
+    if k == 0 {
+        return Ok(vec![]);
+    }
+
+    let mut radius = if initial_radius > 0.0 {
+        initial_radius
+    } else {
+        1.0
+    };
+
+    loop {
+        let mut res = search(radius)?;
+
+        // An infinite radius covers every point with a well-defined distance, so growing it further is pointless.
+        if res.len() >= k || radius.is_infinite() {
+            res.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+            res.truncate(k);
+            return Ok(res);
+        }
+
+        radius *= 2.0;
+    }
+}
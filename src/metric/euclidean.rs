@@ -1,6 +1,6 @@
 use std::ops::{Deref, Sub};
 
-use super::Metric;
+use super::{Metric, Supermetric};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
@@ -71,13 +71,20 @@ where
         // Euclidean distance is the sqrt of the sum of (point1 - point2)^2 for each dimension.
         self.0
             .into_iter()
-            .zip(rhs.0.into_iter())
+            .zip(&rhs.0)
             .map(|(x, y)| (x.sub(*y)).powi(2))
             .sum::<f64>()
             .sqrt()
     }
 }
 
+impl<T> Supermetric for Euclidean<T>
+where
+    for<'a> &'a T: IntoIterator<Item = &'a f64>,
+    T: Clone,
+{
+}
+
 #[cfg(feature = "serde")]
 impl<T> Serialize for Euclidean<T>
 where
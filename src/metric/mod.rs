@@ -19,3 +19,15 @@ pub trait Metric: Clone {
     /// **It is the responsibility of the implementer to ensure that the axiom are met.**
     fn distance(&self, rhs: &Self) -> f64;
 }
+
+/// Marker trait for metrics which are also supermetrics.
+///
+/// A supermetric space has the four-point property: any four points in the space can be embedded in three-dimensional
+/// Euclidean space while preserving the distances between them. Euclidean, Cosine, Jensen-Shannon and Triangular
+/// distances all have this property.
+///
+/// Supermetric spaces allow [four-point exclusion](crate::Builder::four_point), which is usually much tighter than the
+/// three-point exclusion available in any metric space.
+///
+/// **It is the responsibility of the implementer to ensure that the four-point property holds.**
+pub trait Supermetric: Metric {}
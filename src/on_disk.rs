@@ -1,20 +1,32 @@
-use crate::builder::Builder;
-use crate::exclusions::{BallExclusion, ExclusionSync, SheetExclusion};
+use crate::builder::{BuildError, Builder, Params};
+use crate::compress::{self, Runs};
+use crate::container::{self, Reader, Writer};
+use crate::cull::{self, CullError, Cullable};
+use crate::exclusions::{ExclusionSync, ZoneDef};
 use crate::metric::Metric;
+use crate::persist::PersistError;
+use crate::store::{FixedDimension, Points, RowWriter};
+use crate::QueryStats;
 
-use bitvec::prelude::*;
-use itertools::{Either, Itertools};
+use bitvec_simd::{BitBlock, BitVec};
+use itertools::Either;
 use rayon::prelude::*;
 use std::{
+    borrow::Cow,
     fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
 };
 use thiserror::Error;
+use wide::u64x4;
 
 /// On-disk BitPart.
 ///
 /// BitPart variant which stores partitioning data on disk. Instead of holding a vector of bitsets in memory, this struct
-/// holds a vector of [`Mmap`](memmap2::Mmap)s and only deserializes the relevant columns into memory at query-time.
+/// memory-maps a single index file and only reads the relevant columns at query-time.
 ///
 /// The BitPart data structure consists of three components: the dataset itself, information about exclusion zones, and a
 /// vector of bitsets which represent the partitioning data for each point and exclusion zone. For a given query,
@@ -25,17 +37,57 @@ use thiserror::Error;
 /// As a benchmark figure, on the default setting of 40 reference points, `40 * 5 = 200` balls and `40c2 = 780` plane exclusions are made.
 /// At 20 dimensions, each point requires `20 * 64 = 1280` bits of storage plus `200 + 780 = 980` bits of partitioning data.
 ///
-/// Unlike [`Sequential`](crate::Sequential) and [`Parallel`](crate::Parallel), this struct usees [`bitvec`](bitvec::vec::BitVec)
-/// bitvectors. They are not SIMD-optimised so expect worse performance in addition to the overhead from memory mapping (IO and deser).
+/// Each column is split into one segment per block of `block_size` points, which is stored, compressed and read separately. A query
+/// only reads the segments of the blocks it touches, and stops reading a block as soon as no candidates remain in it. Segments are
+/// stored as raw little-endian `u64` words, aligned and padded to 256-bit lanes so that they are laid out exactly like the bitsets of
+/// [`Sequential`](crate::Sequential) and [`Parallel`](crate::Parallel). On little-endian platforms they are viewed in the memory map
+/// without being copied, and each block is filtered with the same SIMD lane operations as `Parallel`, directly over the mapped words.
+/// Segments of at least a page are aligned to page boundaries, so blocks processed in parallel never share a page; a `block_size`
+/// which is a multiple of 32768 avoids padding. Segments which are smaller run-length coded, such as blocks containing almost all or
+/// almost none of a zone, are stored that way instead and decoded when queried. Expect some overhead from memory mapping (IO).
+///
+/// The index file starts with a versioned header and ends with a table recording the exclusion zones, reference points, block size and
+/// dataset length, along with the location and checksum of every segment. An existing directory can be reopened with [`open`](Disk::open)
+/// instead of being rebuilt, and checked for corruption with [`verify`](Disk::verify).
+///
+/// For datasets of [fixed-dimension](crate::FixedDimension) vectors, the points themselves can also be stored on disk with
+/// [`build_on_disk_mapped`](crate::Builder::build_on_disk_mapped), in which case only candidate points are read during verification.
+/// Datasets which do not fit in memory even while the data structure is being built can be read in chunks with
+/// [`build_on_disk_streaming`](crate::Builder::build_on_disk_streaming).
+///
+/// When zones are [culled](crate::Cullable), the index file is rewritten without them.
+/// Culling by similarity or for a workload loads every column into memory.
+///
+/// Points can be [inserted](Disk::insert_batch) after the data structure is built, in which case new segments are appended to the
+/// index file.
 ///
 /// `Disk` is parallelised.
 pub struct Disk<'a, T> {
-    dataset: Vec<T>,
+    dataset: Points<T>,
     exclusions: Vec<Box<dyn ExclusionSync<T> + 'a>>,
-    bitset: Vec<memmap2::Mmap>,
+    index: Reader,
+    path: PathBuf,
     block_size: usize,
+    ref_points: Vec<usize>,
+    zones: Vec<ZoneDef>,
+    params: Params,
 }
 
+/// Name of the index file in a [`Disk`] directory.
+const INDEX: &str = "index";
+/// Name of the index file in a [`Disk`] directory while it is being rewritten.
+const INDEX_TMP: &str = "index.tmp";
+/// Name of the point store in a [`Disk`] directory.
+const POINTS: &str = "points";
+/// Name of the file holding partitioning data chunk by chunk during a [streaming build](crate::Builder::build_on_disk_streaming).
+const CHUNKS: &str = "chunks.tmp";
+
+/// Partitioning data for a single exclusion zone, as the [dense words](compress::dense_words) of each of its blocks one after the other.
+type Column = [u64];
+
+/// Metadata stored in the table of an index file: dataset length, builder parameters, block size, reference points and zones.
+type Manifest = (u64, Params, u64, Vec<usize>, Vec<ZoneDef>);
+
 impl<T> crate::BitPart<T> for Disk<'_, T>
 where
     T: Metric + Send + Sync,
@@ -43,61 +95,57 @@ where
     type Error = DiskError;
 
     fn range_search(&self, point: T, threshold: f64) -> Result<Vec<(T, f64)>, Self::Error> {
-        let (ins, outs): (Vec<usize>, Vec<usize>) = self
-            .exclusions
-            .par_iter()
-            .enumerate()
-            .filter_map(|(idx, ez)| {
-                if ez.must_be_in(&point, threshold) {
-                    Some(Either::Left(idx))
-                } else if ez.must_be_out(&point, threshold) {
-                    Some(Either::Right(idx))
-                } else {
-                    None
-                }
-            })
-            .partition_map(|x| x);
-
-        let ins = ins
-            .into_par_iter()
-            .map(|idx| bincode::deserialize::<BitVec>(self.bitset.get(idx).unwrap()))
-            .collect::<Result<Vec<_>, bincode::Error>>()?;
-
-        let outs = outs
+        Ok(self
+            .search(&point, threshold)?
             .into_par_iter()
-            .map(|idx| bincode::deserialize::<BitVec>(self.bitset.get(idx).unwrap()))
-            .collect::<Result<Vec<_>, bincode::Error>>()?;
-
-        let res = self
-            .dataset
-            .par_chunks(self.block_size)
-            .enumerate()
-            .flat_map(|(blk_idx, points)| {
-                let from = blk_idx * self.block_size;
-                let to = (blk_idx * self.block_size) + points.len();
-
-                let blk_ins = ins.iter().map(|bv| &bv[from..to]).collect::<Vec<_>>();
-                let blk_outs = outs.iter().map(|bv| &bv[from..to]).collect::<Vec<_>>();
+            .map(|(idx, dist)| (self.dataset.get(idx).into_owned(), dist))
+            .collect())
+    }
 
-                let len = points.len();
+    fn range_search_indices(
+        &self,
+        point: T,
+        threshold: f64,
+    ) -> Result<Vec<(usize, f64)>, Self::Error> {
+        self.search(&point, threshold)
+    }
 
-                let ands = blk_ins
-                    .into_iter()
-                    .fold(BitVec::repeat(true, len), |acc: BitVec, v| acc & v);
+    fn range_search_ref(&self, point: T, threshold: f64) -> Result<Vec<(&T, f64)>, Self::Error> {
+        let dataset = self.dataset.in_memory().ok_or(DiskError::PointsOnDisk)?;
+        Ok(self
+            .search(&point, threshold)?
+            .into_par_iter()
+            .map(|(idx, dist)| (&dataset[idx], dist))
+            .collect())
+    }
 
-                let nots = !blk_outs
-                    .into_iter()
-                    .fold(BitVec::repeat(false, len), |acc: BitVec, v| acc | v);
+    fn range_search_with_stats(
+        &self,
+        point: T,
+        threshold: f64,
+    ) -> Result<(Vec<(T, f64)>, QueryStats), Self::Error> {
+        let (res, stats) = self.search_with_stats(&point, threshold)?;
 
-                let res = ands & nots;
+        Ok((
+            res.into_par_iter()
+                .map(|(idx, dist)| (self.dataset.get(idx).into_owned(), dist))
+                .collect(),
+            stats,
+        ))
+    }
 
-                res.iter_ones().map(|idx| &points[idx]).collect::<Vec<_>>()
-            })
-            .map(|pt| (pt.clone(), point.distance(pt)))
-            .filter(|(_, d)| *d <= threshold)
-            .collect::<Vec<_>>();
+    fn knn_search(&self, point: T, k: usize) -> Result<Vec<(T, f64)>, Self::Error> {
+        let res = crate::expanding_knn(
+            self.dataset.len(),
+            k,
+            self.params.radius_increment,
+            |radius| self.search(&point, radius),
+        )?;
 
-        Ok(res)
+        Ok(res
+            .into_iter()
+            .map(|(idx, dist)| (self.dataset.get(idx).into_owned(), dist))
+            .collect())
     }
 
     fn len(&self) -> usize {
@@ -122,215 +170,1883 @@ where
         builder: Builder<T>,
         path: P,
         block_size: Option<usize>,
-    ) -> Result<Self, DiskError>
+    ) -> Result<Self, BuildError>
     where
         P: AsRef<Path> + 'a,
     {
         let block_size = block_size.unwrap_or(builder.dataset.len());
         let path = path.as_ref().to_owned();
-        // TODO: actually randomise this
-        let ref_points = &builder.dataset[0..(builder.ref_points as usize)];
-        let mut exclusions = Self::ball_exclusions(&builder, ref_points);
-        exclusions.extend(Self::sheet_exclusions(&builder, ref_points));
-        let bitset = Self::make_bitset(block_size, &builder, path, &exclusions)?;
+        builder.validate()?;
+        let ref_points = builder.ref_point_indices();
+        let points = ref_points
+            .iter()
+            .map(|&idx| builder.dataset[idx].clone())
+            .collect::<Vec<_>>();
+        let zones = builder.zone_defs(&points)?;
+        let params = builder.params();
+        let mut exclusions = Self::builtin_exclusions(&points, &zones);
+        exclusions.extend(
+            builder
+                .exclusions_sync
+                .iter()
+                .map(|ez| Box::new(Arc::clone(ez)) as Box<dyn ExclusionSync<T>>),
+        );
+
+        let mut writer = Writer::create(&path.join(INDEX), alignment(block_size))?;
+        // Columns are computed in parallel a few at a time, so that only a few are held in memory before being written.
+        for chunk in exclusions.chunks(rayon::current_num_threads()) {
+            let columns = chunk
+                .par_iter()
+                .map(|ez| {
+                    let len = builder.dataset.len();
+                    let words = compress::pack(len, builder.dataset.iter().map(|pt| ez.is_in(pt)));
+                    segments(&words, len, block_size)
+                })
+                .collect::<Vec<_>>();
+            columns
+                .iter()
+                .flatten()
+                .try_for_each(|(segment, compressed)| writer.push(segment, *compressed))?;
+        }
+        writer.finish(&(
+            builder.dataset.len() as u64,
+            &params,
+            block_size as u64,
+            &ref_points,
+            &zones,
+        ))?;
+        let (index, _) = Reader::open::<Manifest>(&path.join(INDEX))?;
+
         Ok(Self {
-            dataset: builder.dataset,
-            bitset,
+            dataset: Points::Memory(builder.dataset),
+            index,
+            path,
             exclusions,
             block_size,
+            ref_points,
+            zones,
+            params,
         })
     }
 
-    fn ball_exclusions(
-        builder: &Builder<T>,
-        ref_points: &[T],
-    ) -> Vec<Box<dyn ExclusionSync<T> + 'a>> {
-        let radii = [
-            builder.mean_distance - 2.0 * builder.radius_increment,
-            builder.mean_distance - builder.radius_increment,
-            builder.mean_distance,
-            builder.mean_distance + builder.radius_increment,
-            builder.mean_distance + 2.0 * builder.radius_increment,
-        ];
-
-        ref_points
-            .iter()
-            .cartesian_product(radii.into_iter())
-            .map(|(point, radius)| {
-                Box::new(BallExclusion::new(point.clone(), radius)) as Box<dyn ExclusionSync<T>>
-            })
-            .collect()
+    /// Open a directory previously written by [`build_on_disk`](crate::Builder::build_on_disk), without rebuilding it.
+    ///
+    /// `dataset` must be the dataset the data structure was built from. Columns are mapped read-only, so several processes can
+    /// share one prebuilt directory as long as none of them [culls](crate::Cullable) it.
+    ///
+    /// # Errors
+    /// Returns [`PersistError::DatasetLength`] if `dataset` does not have the same number of points as the dataset the data
+    /// structure was built from, and [`PersistError::CustomZones`] if it was built with custom exclusion zones, as these cannot
+    /// be recreated.
+    pub fn open<P, I>(path: P, dataset: I) -> Result<Self, DiskError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = T>,
+    {
+        Self::open_with(path.as_ref(), |_| {
+            Ok(Points::Memory(dataset.into_iter().collect()))
+        })
     }
 
-    fn sheet_exclusions(
-        _builder: &Builder<T>,
-        ref_points: &[T],
-    ) -> Vec<Box<dyn ExclusionSync<T> + 'a>> {
-        ref_points
-            .iter()
-            .combinations(2)
-            .map(|x| {
-                Box::new(SheetExclusion::new(x[0].clone(), x[1].clone(), 0.0))
-                    as Box<dyn ExclusionSync<T>>
+    /// Open the directory at `path`, using the points returned by `dataset` given the length of the dataset
+    /// the data structure was built from.
+    fn open_with(
+        path: &Path,
+        dataset: impl FnOnce(usize) -> Result<Points<T>, DiskError>,
+    ) -> Result<Self, DiskError> {
+        let (index, (len, params, block_size, ref_points, zones)) =
+            Reader::open::<Manifest>(&path.join(INDEX))?;
+
+        let dataset = dataset(len as usize)?;
+        if dataset.len() as u64 != len {
+            return Err(PersistError::DatasetLength {
+                expected: len as usize,
+                found: dataset.len(),
+            }
+            .into());
+        }
+        if zones.contains(&ZoneDef::Custom) {
+            return Err(PersistError::CustomZones.into());
+        }
+        if block_size == 0 {
+            return Err(PersistError::Corrupt.into());
+        }
+        let (len, block_size) = (len as usize, block_size as usize);
+        let blocks = len.div_ceil(block_size);
+        // Runs are only parsed when they are read, so that corrupt runs can be found with `verify`.
+        if index.len() != zones.len() * blocks
+            || !(0..index.len()).all(|idx| {
+                let block_len = block_size.min(len - idx % blocks * block_size);
+                index.is_compressed(idx)
+                    || index.segment(idx).len() == compress::dense_bytes(block_len)
             })
-            .collect()
+            || ref_points.iter().any(|&idx| idx >= dataset.len())
+            || !zones.iter().all(|zone| zone.is_valid(ref_points.len()))
+        {
+            return Err(PersistError::Corrupt.into());
+        }
+
+        let points = ref_points
+            .iter()
+            .map(|&idx| dataset.get(idx).into_owned())
+            .collect::<Vec<_>>();
+        Ok(Self {
+            exclusions: Self::builtin_exclusions(&points, &zones),
+            dataset,
+            index,
+            path: path.to_owned(),
+            block_size,
+            ref_points,
+            zones,
+            params,
+        })
     }
 
-    fn make_bitset(
-        _block_size: usize,
-        builder: &Builder<T>,
-        path: PathBuf,
-        exclusions: &[Box<dyn ExclusionSync<T> + 'a>],
-    ) -> Result<Vec<memmap2::Mmap>, DiskError> {
-        exclusions
-            .par_iter()
-            .enumerate()
-            .map(|(idx, ez)| Self::make_mmap(&builder.dataset, path.clone(), idx, ez.as_ref()))
-            .collect::<Result<Vec<_>, _>>()
+    /// Insert `point` into the dataset, returning its index.
+    ///
+    /// See [`insert_batch`](Disk::insert_batch) for details.
+    pub fn insert(&mut self, point: T) -> Result<usize, DiskError> {
+        Ok(self.insert_batch([point])?.start)
     }
 
-    fn make_mmap(
-        dataset: &[T],
-        mut path: PathBuf,
-        index: usize,
-        ez: &(dyn ExclusionSync<T> + 'a),
-    ) -> Result<memmap2::Mmap, DiskError> {
-        let bv = dataset.iter().map(|pt| ez.is_in(pt)).collect::<BitVec>();
+    /// Insert `points` into the dataset, returning the range of their indices.
+    ///
+    /// Each point is checked against the existing exclusion zones, which are not redefined. If the dataset grows to look very
+    /// different from the points the data structure was built from, rebuilding it gives more effective zones.
+    ///
+    /// Points fill the last block before new blocks are opened. The segments of the last block are rewritten and appended to the
+    /// index file along with those of new blocks, leaving the old segments behind as unused space, and the index file is compacted
+    /// once more than half of it is unused. Inserting points in batches therefore writes far less than inserting them one by one.
+    ///
+    /// Points are appended to the point store if the data structure has one. Otherwise, the inserted points must be included in
+    /// the dataset given to [`open`](Disk::open).
+    ///
+    /// # Errors
+    /// Returns [`DiskError::Dimension`] without inserting anything if a point does not have the same number of dimensions as the
+    /// points in the point store. If the points cannot be written to the point store, it is truncated back to its old length and the
+    /// index file is left covering only the points which were already there.
+    pub fn insert_batch(
+        &mut self,
+        points: impl IntoIterator<Item = T>,
+    ) -> Result<Range<usize>, DiskError> {
+        let points = points.into_iter().collect::<Vec<_>>();
+        let start = self.dataset.len();
+        let len = start + points.len();
+        if points.is_empty() {
+            return Ok(start..len);
+        }
+        self.dataset.check(&points)?;
+
+        // Segments of full blocks are kept, and those of a partly full last block are rewritten.
+        let old_blocks = self.n_blocks();
+        let kept = start / self.block_size;
+        let from = kept * self.block_size;
+        let mut writer = Writer::append(&self.path.join(INDEX), alignment(self.block_size))?;
+        let zone_indices = (0..self.exclusions.len()).collect::<Vec<_>>();
+        for chunk in zone_indices.chunks(rayon::current_num_threads()) {
+            let columns = chunk
+                .par_iter()
+                .map(|&idx| {
+                    let last = match kept < old_blocks {
+                        true => self.segment(idx, kept)?.into_bools(),
+                        false => vec![],
+                    };
+                    let ez = &self.exclusions[idx];
+                    let bits = last.into_iter().chain(points.iter().map(|pt| ez.is_in(pt)));
+                    Ok(segments(
+                        &compress::pack(len - from, bits),
+                        len - from,
+                        self.block_size,
+                    ))
+                })
+                .collect::<Result<Vec<_>, DiskError>>()?;
+            for (&idx, column) in chunk.iter().zip(&columns) {
+                for blk_idx in 0..kept {
+                    writer.keep(&self.index, idx * old_blocks + blk_idx);
+                }
+                column
+                    .iter()
+                    .try_for_each(|(segment, compressed)| writer.push(segment, *compressed))?;
+            }
+        }
 
-        path.push(format!("{}.bincode", index));
-        let file = File::create(&path)?;
-        bincode::serialize_into(file, &bv)?;
+        // The new table only refers to the inserted points once they are safely in the point store.
+        let store = self.path.join(POINTS);
+        self.dataset.append(&points, &store)?;
+        if let Err(e) = writer.finish(&(
+            len as u64,
+            &self.params,
+            self.block_size as u64,
+            &self.ref_points,
+            &self.zones,
+        )) {
+            self.dataset.truncate(&store).ok();
+            return Err(e);
+        }
 
-        unsafe { Ok(memmap2::Mmap::map(&File::open(path)?)?) }
+        self.dataset.extend(points, &store)?;
+        (self.index, _) = Reader::open::<Manifest>(&self.path.join(INDEX))?;
+        if self.index.is_sparse(alignment(self.block_size)) {
+            self.cull(&vec![true; self.exclusions.len()])?;
+        }
+        Ok(start..len)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{metric::Euclidean, BitPart};
-    use sisap_data::{colors::parse_colors, nasa::parse_nasa, parser::parse};
-    use std::fs;
+    /// Perform a range search for each point in `queries`, using the same radius `threshold` for all of them.
+    ///
+    /// Returns one vector of results per query, in the same order as `queries`.
+    /// See [`range_search_batch_with_radii`](crate::Disk::range_search_batch_with_radii) for details.
+    pub fn range_search_batch(
+        &self,
+        queries: &[T],
+        threshold: f64,
+    ) -> Result<Vec<Vec<(T, f64)>>, DiskError> {
+        let queries = queries.iter().map(|q| (q, threshold)).collect::<Vec<_>>();
+        Ok(self.resolve_batch(self.search_batch(&queries)?))
+    }
 
-    use super::*;
+    /// Perform a range search for each `(point, threshold)` pair in `queries`.
+    ///
+    /// Returns one vector of results per query, in the same order as `queries`.
+    ///
+    /// This is faster than calling [`range_search`](crate::BitPart::range_search) in a loop. The partitioning data of each block
+    /// is read at most once for the whole batch, every query is filtered against every block in parallel, and the candidates in
+    /// each block are verified as soon as it is filtered.
+    pub fn range_search_batch_with_radii(
+        &self,
+        queries: &[(T, f64)],
+    ) -> Result<Vec<Vec<(T, f64)>>, DiskError> {
+        let queries = queries.iter().map(|(q, t)| (q, *t)).collect::<Vec<_>>();
+        Ok(self.resolve_batch(self.search_batch(&queries)?))
+    }
 
-    pub(crate) const NASA: &str = include_str!("../sisap-data/src/nasa.ascii");
-    pub(crate) const COLORS: &str = include_str!("../sisap-data/src/colors.ascii");
+    fn resolve_batch(&self, results: Vec<Vec<(usize, f64)>>) -> Vec<Vec<(T, f64)>> {
+        results
+            .into_par_iter()
+            .map(|res| {
+                res.into_iter()
+                    .map(|(idx, dist)| (self.dataset.get(idx).into_owned(), dist))
+                    .collect()
+            })
+            .collect()
+    }
 
-    fn test<T>(dataset: &Vec<T>, bitpart: &Disk<T>, query: T, threshold: f64)
-    where
-        for<'a> T: Metric + Send + Sync + 'a,
-    {
-        let res = bitpart.range_search(query.clone(), threshold).unwrap();
+    /// Range search returning the indices of matching points, along with their distance from `point`.
+    fn search(&self, point: &T, threshold: f64) -> Result<Vec<(usize, f64)>, DiskError> {
+        Ok(self.search_with_stats(point, threshold)?.0)
+    }
 
-        // Check all points within threshold
-        assert!(res
-            .iter()
-            .all(|(point, _)| point.distance(&query) <= threshold));
+    /// Range search returning the indices of matching points, along with statistics about the query.
+    fn search_with_stats(
+        &self,
+        point: &T,
+        threshold: f64,
+    ) -> Result<(Vec<(usize, f64)>, QueryStats), DiskError> {
+        let start = Instant::now();
+        let (ins, outs) = self.classify(point, threshold);
+        let exclusion_time = start.elapsed();
 
-        // Check results match up with linear search
-        let brute_force = dataset
-            .into_iter()
-            .map(|pt| pt.distance(&query))
-            .filter(|d| *d <= threshold)
-            .count();
+        let start = Instant::now();
+        let candidates = self
+            .blocks()
+            .map(|(blk_idx, from, len)| {
+                Self::block_candidates(
+                    from,
+                    len,
+                    ins.iter().map(|&idx| self.segment_words(idx, blk_idx)),
+                    outs.iter().map(|&idx| self.segment_words(idx, blk_idx)),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+        let filter_time = start.elapsed();
 
-        assert_eq!(res.len(), brute_force);
+        let start = Instant::now();
+        let res = candidates
+            .par_iter()
+            .map(|&idx| (idx, point.distance(&self.dataset.get(idx))))
+            .filter(|(_, d)| *d <= threshold)
+            .collect::<Vec<_>>();
+        let verify_time = start.elapsed();
+
+        let stats = QueryStats {
+            must_be_in: ins.len(),
+            must_be_out: outs.len(),
+            undecided: self.exclusions.len() - ins.len() - outs.len(),
+            candidates: candidates.len(),
+            distance_calls: candidates.len(),
+            exclusion_time,
+            filter_time,
+            verify_time,
+        };
+
+        Ok((res, stats))
     }
 
-    #[test]
-    fn sisap_nasa_par() {
-        std::fs::remove_dir_all("/tmp/sisap_nasa_par/").ok();
-        let nasa = parse_nasa(NASA)
-            .unwrap()
-            .into_iter()
-            .map(Euclidean::new)
+    /// Range search over many queries at once, returning the indices of matching points for each query.
+    fn search_batch(&self, queries: &[(&T, f64)]) -> Result<Vec<Vec<(usize, f64)>>, DiskError> {
+        let zones = queries
+            .par_iter()
+            .map(|(point, threshold)| self.classify(point, *threshold))
             .collect::<Vec<_>>();
 
-        let bitpart = Builder::new(nasa.clone(), 40)
-            .build_on_disk("/tmp/sisap_nasa_par/", Some(8192))
-            .unwrap();
-        let query = nasa[317].clone();
-        let threshold = 1.0;
+        // Only read zones which are relevant to at least one query.
+        let mut needed = vec![false; self.exclusions.len()];
+        for (ins, outs) in zones.iter() {
+            for &idx in ins.iter().chain(outs.iter()) {
+                needed[idx] = true;
+            }
+        }
 
-        test(&nasa, &bitpart, query, threshold);
-        std::fs::remove_dir_all("/tmp/sisap_nasa_par/").unwrap();
+        // The segments of a block are read once for all queries. Each query is then filtered against them and its candidates are
+        // verified straight away, so only matching points are kept. Blocks and the queries within each block are both processed
+        // in parallel.
+        let found = self
+            .blocks()
+            .map(|(blk_idx, from, len)| {
+                let segments = needed
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, needed)| {
+                        needed.then(|| self.segment_words(idx, blk_idx)).transpose()
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                zones
+                    .par_iter()
+                    .zip(queries)
+                    .map(|((ins, outs), (point, threshold))| {
+                        Ok(Self::block_candidates(
+                            from,
+                            len,
+                            ins.iter()
+                                .map(|&idx| Ok(Cow::Borrowed(&**segments[idx].as_ref().unwrap()))),
+                            outs.iter()
+                                .map(|&idx| Ok(Cow::Borrowed(&**segments[idx].as_ref().unwrap()))),
+                        )?
+                        .into_iter()
+                        .map(|idx| (idx, point.distance(&self.dataset.get(idx))))
+                        .filter(|(_, d)| d <= threshold)
+                        .collect::<Vec<_>>())
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, DiskError>>()?;
+
+        let mut res = vec![vec![]; queries.len()];
+        for block in found {
+            for (res, found) in res.iter_mut().zip(block) {
+                res.extend(found);
+            }
+        }
+        Ok(res)
     }
 
-    #[test]
-    fn sisap_colors_par() {
-        std::fs::remove_dir_all("/tmp/sisap_colors_par/").ok();
-        let colors = parse_colors(COLORS)
-            .unwrap()
-            .into_iter()
-            .map(Euclidean::new)
-            .collect::<Vec<_>>();
+    /// Sort exclusion zones into those `point` must be in and those it must be out of.
+    fn classify(&self, point: &T, threshold: f64) -> (Vec<usize>, Vec<usize>) {
+        self.exclusions
+            .par_iter()
+            .enumerate()
+            .filter_map(|(idx, ez)| {
+                if ez.must_be_in(point, threshold) {
+                    Some(Either::Left(idx))
+                } else if ez.must_be_out(point, threshold) {
+                    Some(Either::Right(idx))
+                } else {
+                    None
+                }
+            })
+            .partition_map(|x| x)
+    }
 
-        let bitpart = Builder::new(colors.clone(), 40)
-            .build_on_disk("/tmp/sisap_colors_par/", Some(8192))
-            .unwrap();
-        let query = colors[70446].clone();
-        let threshold = 0.5;
+    /// Number of blocks the dataset is split into.
+    fn n_blocks(&self) -> usize {
+        self.dataset.len().div_ceil(self.block_size)
+    }
 
-        test(&colors, &bitpart, query, threshold);
-        std::fs::remove_dir_all("/tmp/sisap_colors_par/").unwrap();
+    /// Number of points in block `blk_idx`.
+    fn block_len(&self, blk_idx: usize) -> usize {
+        self.block_size
+            .min(self.dataset.len() - blk_idx * self.block_size)
     }
 
-    #[test]
-    fn nearest_neighbour() {
-        std::fs::remove_dir_all("/tmp/nn/").ok();
+    /// The dense words of the segment holding the partitioning data of zone `idx` in block `blk_idx`, viewed in place where possible.
+    ///
+    /// Returns [`PersistError::Corrupt`] if the segment is run-length coded and its runs are malformed.
+    fn segment_words(&self, idx: usize, blk_idx: usize) -> Result<Cow<'_, Column>, DiskError> {
+        let seg_idx = idx * self.n_blocks() + blk_idx;
+        if self.index.is_compressed(seg_idx) {
+            let runs = Runs::from_bytes(self.index.segment(seg_idx), self.block_len(blk_idx))
+                .ok_or(PersistError::Corrupt)?;
+            return Ok(Cow::Owned(runs.words()));
+        }
 
-        let points = parse(&fs::read_to_string("data/100k_d20_flat.ascii").unwrap())
-            .unwrap()
-            .1
-             .1
-            .into_iter()
-            .map(Euclidean::new)
-            .collect::<Vec<_>>();
+        Ok(self.index.words(seg_idx))
+    }
 
-        let nns: Vec<Vec<(usize, f64)>> =
-            serde_json::from_str(&fs::read_to_string("data/100k_d20_flat.json").unwrap()).unwrap();
+    /// The partitioning data of zone `idx` in block `blk_idx`.
+    fn segment(&self, idx: usize, blk_idx: usize) -> Result<BitVec, DiskError> {
+        Ok(BitVec::from_slice_copy(
+            &self.segment_words(idx, blk_idx)?,
+            self.block_len(blk_idx),
+        ))
+    }
 
-        let queries = points
-            .iter()
-            .cloned()
-            .zip(nns.into_iter())
-            .map(|(pt, nn)| (pt, nn.last().unwrap().1))
-            .take(1000)
-            .collect::<Vec<_>>();
+    /// The partitioning data for a single exclusion zone.
+    fn column(&self, idx: usize) -> Result<Vec<u64>, DiskError> {
+        let mut column = vec![];
+        for blk_idx in 0..self.n_blocks() {
+            column.extend_from_slice(&self.segment_words(idx, blk_idx)?);
+        }
+        Ok(column)
+    }
 
-        let bitpart = Builder::new(points.clone(), 40)
-            .build_on_disk("/tmp/nn/", Some(8192))
-            .unwrap();
+    /// The partitioning data for every exclusion zone.
+    fn columns(&self) -> Result<Vec<Vec<u64>>, DiskError> {
+        (0..self.exclusions.len())
+            .into_par_iter()
+            .map(|idx| self.column(idx))
+            .collect()
+    }
 
-        for (query, threshold) in queries {
-            test(&points, &bitpart, query, threshold);
+    /// The index, index of the first point and number of points of each block.
+    fn blocks(&self) -> impl IndexedParallelIterator<Item = (usize, usize, usize)> + '_ {
+        (0..self.n_blocks())
+            .into_par_iter()
+            .map(move |blk_idx| (blk_idx, blk_idx * self.block_size, self.block_len(blk_idx)))
+    }
+
+    /// Dataset indices of candidate points in the block of `len` points starting at `from`,
+    /// given the segments of the zones a query must be in and out of.
+    ///
+    /// Segments are combined into a single accumulator with the lane operations of [`BitVec`], directly over their words, and are
+    /// only read until no candidates remain in the block.
+    fn block_candidates<'b>(
+        from: usize,
+        len: usize,
+        ins: impl Iterator<Item = Result<Cow<'b, Column>, DiskError>>,
+        outs: impl Iterator<Item = Result<Cow<'b, Column>, DiskError>>,
+    ) -> Result<Vec<usize>, DiskError> {
+        let mut res = ones(len);
+
+        for segment in ins {
+            if !and_lanes(&mut res, &lanes(segment?)) {
+                return Ok(vec![]);
+            }
         }
 
-        std::fs::remove_dir_all("/tmp/nn/").unwrap();
+        for segment in outs {
+            if !and_not_lanes(&mut res, &lanes(segment?)) {
+                return Ok(vec![]);
+            }
+        }
+
+        Ok(res
+            .iter()
+            .flat_map(|lane| lane.to_array())
+            .enumerate()
+            .flat_map(|(word_idx, mut word)| {
+                std::iter::from_fn(move || {
+                    (word != 0).then(|| {
+                        let bit = word.trailing_zeros() as usize;
+                        word &= word - 1;
+                        from + word_idx * u64::BITS as usize + bit
+                    })
+                })
+            })
+            .collect())
+    }
+
+    /// Recreate the built-in exclusion zones from their definitions. Custom zones are skipped.
+    fn builtin_exclusions(
+        ref_points: &[T],
+        zones: &[ZoneDef],
+    ) -> Vec<Box<dyn ExclusionSync<T> + 'a>> {
+        zones
+            .iter()
+            .filter_map(|zone| zone.exclusion(ref_points))
+            .map(|ez| Box::new(ez) as Box<dyn ExclusionSync<T>>)
+            .collect()
     }
 }
 
-impl<T> Builder<T>
+impl<'a, T> Disk<'a, T>
 where
-    for<'a> T: Metric + Send + Sync + 'a,
+    T: Metric + FixedDimension + Send + Sync,
+    dyn ExclusionSync<T>: 'a,
 {
-    /// Construct a [`Disk`](crate::Disk).
+    /// Open a directory previously written by [`build_on_disk_mapped`](crate::Builder::build_on_disk_mapped), without rebuilding
+    /// it or loading the dataset into memory.
     ///
-    /// `path` should be a path for the directory in which partitioning data will be stored.
-    /// This function uses [`create_dir`](std::fs::create_dir) to create the directory, *not* [`create_dir_all`](std::fs::create_dir_all).
+    /// See [`open`](Disk::open) for details.
     ///
-    /// # Panics
-    /// This function will panic if the `create_dir` call is unsuccessful.
-    pub fn build_on_disk<'a, P>(
-        self,
+    /// # Errors
+    /// Returns [`DiskError::Dimension`] if `T` has a fixed number of [dimensions](FixedDimension::dimensions) which differs
+    /// from that of the points in the point store.
+    pub fn open_mapped<P>(path: P) -> Result<Self, DiskError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        Self::open_with(path, |len| Points::map(&path.join(POINTS), len))
+    }
+
+    /// Build the data structure from the builder's dataset followed by `rest`, reading `chunk_size` points at a time.
+    ///
+    /// Zones are defined from the builder's dataset alone. The partitioning data of each chunk is written to a temporary file
+    /// as it is computed, and assembled into columns once every point has been read.
+    pub(crate) fn stream<P, E>(
+        mut builder: Builder<T>,
+        rest: impl IntoIterator<Item = Result<T, E>>,
         path: P,
         block_size: Option<usize>,
-    ) -> Result<Disk<'a, T>, DiskError>
+        chunk_size: usize,
+    ) -> Result<Self, BuildError>
     where
-        P: AsRef<std::path::Path> + 'a,
+        P: AsRef<Path> + 'a,
+        E: Into<DiskError>,
     {
-        std::fs::create_dir(&path).unwrap();
-        Disk::setup(self, path, block_size)
+        // Chunks are a whole number of lanes, so that the words of consecutive chunks can be concatenated.
+        let chunk_size = chunk_size.max(1).next_multiple_of(256);
+        let path = path.as_ref().to_owned();
+        builder.validate()?;
+        let ref_points = builder.ref_point_indices();
+        let points = ref_points
+            .iter()
+            .map(|&idx| builder.dataset[idx].clone())
+            .collect::<Vec<_>>();
+        let zones = builder.zone_defs(&points)?;
+        let params = builder.params();
+        let mut exclusions = Self::builtin_exclusions(&points, &zones);
+        exclusions.extend(
+            builder
+                .exclusions_sync
+                .iter()
+                .map(|ez| Box::new(Arc::clone(ez)) as Box<dyn ExclusionSync<T>>),
+        );
+
+        let mut dataset = std::mem::take(&mut builder.dataset)
+            .into_iter()
+            .map(Ok)
+            .chain(rest.into_iter().map(|pt| pt.map_err(Into::into)));
+        let mut rows = RowWriter::create(&path.join(POINTS))?;
+        // Removed however the build ends. It is declared before the writer and map of the file so that they are dropped first.
+        let chunks_file = RemoveOnDrop(path.join(CHUNKS));
+        let mut chunks = BufWriter::new(File::create(&chunks_file.0)?);
+        let mut len = 0;
+        loop {
+            let chunk = dataset
+                .by_ref()
+                .take(chunk_size)
+                .collect::<Result<Vec<_>, DiskError>>()?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            chunk.iter().try_for_each(|pt| rows.push(pt))?;
+            let bits = exclusions
+                .par_iter()
+                .map(|ez| compress::pack(chunk.len(), chunk.iter().map(|pt| ez.is_in(pt))))
+                .collect::<Vec<_>>();
+            for word in bits.iter().flatten() {
+                chunks.write_all(&word.to_le_bytes())?;
+            }
+            len += chunk.len();
+        }
+        chunks.flush()?;
+        drop(chunks);
+        let dataset = rows.finish()?;
+
+        // Every chunk but the last is full, and holds the words of each zone one after the other.
+        let chunks = unsafe { memmap2::Mmap::map(&File::open(&chunks_file.0)?)? };
+        let full_words = chunk_size / 64;
+        let n_chunks = len.div_ceil(chunk_size);
+        let last_words = compress::dense_words(len - (n_chunks - 1) * chunk_size);
+        let column_words = |zone: usize| {
+            (0..n_chunks)
+                .flat_map(|chunk| {
+                    let words = if chunk == n_chunks - 1 {
+                        last_words
+                    } else {
+                        full_words
+                    };
+                    let start = (chunk * exclusions.len() * full_words + zone * words) * 8;
+                    chunks[start..start + words * 8]
+                        .chunks_exact(8)
+                        .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let block_size = block_size.unwrap_or(len);
+        let mut writer = Writer::create(&path.join(INDEX), alignment(block_size))?;
+        let zone_indices = (0..exclusions.len()).collect::<Vec<_>>();
+        for chunk in zone_indices.chunks(rayon::current_num_threads()) {
+            let columns = chunk
+                .par_iter()
+                .map(|&zone| segments(&column_words(zone), len, block_size))
+                .collect::<Vec<_>>();
+            columns
+                .iter()
+                .flatten()
+                .try_for_each(|(segment, compressed)| writer.push(segment, *compressed))?;
+        }
+        writer.finish(&(len as u64, &params, block_size as u64, &ref_points, &zones))?;
+        drop(chunks);
+        drop(chunks_file);
+        let (index, _) = Reader::open::<Manifest>(&path.join(INDEX))?;
+
+        Ok(Self {
+            dataset,
+            index,
+            path,
+            exclusions,
+            block_size,
+            ref_points,
+            zones,
+            params,
+        })
+    }
+
+    /// Move the dataset from memory into a point store in the data structure's directory.
+    fn store_points(&mut self) -> Result<(), DiskError> {
+        if let Points::Memory(points) = &self.dataset {
+            self.dataset = Points::store(points, &self.path.join(POINTS))?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Cullable<T> for Disk<'_, T>
+where
+    T: Metric + Send + Sync,
+{
+    type Error = DiskError;
+
+    fn cull_by_similarity(&mut self, threshold: f64) -> Result<(), DiskError> {
+        let columns = self.columns()?;
+
+        let keep =
+            cull::keep_by_similarity(columns.len(), self.dataset.len(), threshold, |i, j| {
+                hamming(&columns[i], &columns[j])
+            });
+
+        self.cull(&keep)
+    }
+
+    fn cull_by_popcnt(&mut self, threshold: f64) -> Result<(), DiskError> {
+        let popcnt = (0..self.exclusions.len())
+            .map(|idx| Ok(count_ones(&self.column(idx)?)))
+            .collect::<Result<Vec<_>, DiskError>>()?;
+
+        self.cull(&cull::keep_by_popcnt(
+            &popcnt,
+            self.dataset.len(),
+            threshold,
+        ))
+    }
+
+    fn select_zones_for_workload(
+        &mut self,
+        sample_queries: &[T],
+        threshold: f64,
+        budget: usize,
+    ) -> Result<(), DiskError> {
+        let decisions = sample_queries
+            .iter()
+            .map(|query| self.classify(query, threshold))
+            .collect::<Vec<_>>();
+
+        let columns = (0..self.exclusions.len())
+            .into_par_iter()
+            .map(|idx| {
+                let mut bits = vec![];
+                for blk_idx in 0..self.n_blocks() {
+                    bits.extend(self.segment(idx, blk_idx)?.into_bools());
+                }
+                Ok(BitVec::from_bool_iterator(bits.into_iter()))
+            })
+            .collect::<Result<Vec<_>, DiskError>>()?;
+
+        self.cull(&cull::keep_for_workload(
+            &columns,
+            &decisions,
+            self.dataset.len(),
+            budget,
+        )?)
+    }
+
+    fn cull_to(&mut self, n_zones: usize) -> Result<(), DiskError> {
+        let columns = self.columns()?;
+        let popcnt = columns.iter().map(|c| count_ones(c)).collect::<Vec<_>>();
+
+        let keep = cull::keep_n(&popcnt, self.dataset.len(), n_zones, |i, j| {
+            hamming(&columns[i], &columns[j])
+        })?;
+
+        self.cull(&keep)
+    }
+}
+
+impl<T> Disk<'_, T> {
+    /// Check the partitioning data of every zone against its checksum, and check that run-length coded data can be decoded,
+    /// returning the indices of the zones whose data is corrupt.
+    ///
+    /// Queries and culls which read corrupt run-length coded data return [`PersistError::Corrupt`]. Corruption of the table at the
+    /// end of the index file, and segments which lie outside of the file or have the wrong size, are detected when the directory is
+    /// [opened](Disk::open).
+    pub fn verify(&self) -> Vec<usize> {
+        let blocks = self.dataset.len().div_ceil(self.block_size);
+        let corrupt = self.index.corrupt();
+        let mut zones = (0..self.index.len())
+            .filter(|seg_idx| {
+                let block_len = self
+                    .block_size
+                    .min(self.dataset.len() - seg_idx % blocks * self.block_size);
+                corrupt.contains(seg_idx)
+                    || (self.index.is_compressed(*seg_idx)
+                        && Runs::from_bytes(self.index.segment(*seg_idx), block_len).is_none())
+            })
+            .map(|seg_idx| seg_idx / blocks)
+            .collect::<Vec<_>>();
+        zones.dedup();
+        zones
+    }
+
+    /// Remove the zones for which `keep` is `false` by rewriting the index file without them.
+    fn cull(&mut self, keep: &[bool]) -> Result<(), DiskError> {
+        let mut zones = self.zones.clone();
+        cull::retain(&mut zones, keep);
+
+        let tmp = self.path.join(INDEX_TMP);
+        let mut writer = Writer::create(&tmp, alignment(self.block_size))?;
+        let blocks = self.dataset.len().div_ceil(self.block_size);
+        for (idx, _) in keep.iter().enumerate().filter(|(_, k)| **k) {
+            for seg_idx in idx * blocks..(idx + 1) * blocks {
+                writer.push(
+                    self.index.segment(seg_idx),
+                    self.index.is_compressed(seg_idx),
+                )?;
+            }
+        }
+        writer.finish(&(
+            self.dataset.len() as u64,
+            &self.params,
+            self.block_size as u64,
+            &self.ref_points,
+            &zones,
+        ))?;
+
+        // The old index is unmapped before it is replaced.
+        (self.index, _) = Reader::open::<Manifest>(&tmp)?;
+        cull::retain(&mut self.exclusions, keep);
+        self.zones = zones;
+        std::fs::rename(tmp, self.path.join(INDEX))?;
+        Ok(())
+    }
+}
+
+/// A temporary file at the given path, which is removed when dropped.
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Alignment of the segments of an index with blocks of `block_size` points.
+///
+/// Segments of at least a page are aligned to page boundaries, so that blocks processed by different threads never share a page.
+fn alignment(block_size: usize) -> u64 {
+    if compress::dense_bytes(block_size) as u64 >= container::PAGE {
+        container::PAGE
+    } else {
+        container::ALIGN
+    }
+}
+
+/// Split a column of `len` bits, given as dense words, into one encoded segment per block of `block_size` points.
+fn segments(words: &[u64], len: usize, block_size: usize) -> Vec<(Vec<u8>, bool)> {
+    (0..len)
+        .step_by(block_size)
+        .map(|from| {
+            let block_len = block_size.min(len - from);
+            let bits = compress::unpack(&words[from / 64..]).skip(from % 64);
+            encode_segment(compress::pack(block_len, bits), block_len)
+        })
+        .collect()
+}
+
+/// Encode a segment of `len` bits, given as dense words, as runs if that is smaller and as little-endian words otherwise.
+/// Returns the bytes to store and whether they are compressed.
+fn encode_segment(words: Vec<u64>, len: usize) -> (Vec<u8>, bool) {
+    match Runs::encode(len, compress::unpack(&words)) {
+        Some(runs) => (runs.to_bytes(), true),
+        None => (
+            words.into_iter().flat_map(u64::to_le_bytes).collect(),
+            false,
+        ),
+    }
+}
+
+/// Number of points in a column. Bits past the end of each block are always zero.
+fn count_ones(column: &Column) -> usize {
+    column.iter().map(|word| word.count_ones() as usize).sum()
+}
+
+/// A 256-bit lane of partitioning data, the unit [`BitVec`] stores and combines bits in.
+type Lane = u64x4;
+
+/// Number of `u64` words in a [`Lane`].
+const LANE_WORDS: usize = 4;
+
+/// Copy dense words into lanes.
+fn to_lanes(words: &Column) -> Vec<Lane> {
+    words
+        .chunks_exact(LANE_WORDS)
+        .map(|lane| Lane::new(lane.try_into().unwrap()))
+        .collect()
+}
+
+/// The dense words of a segment as lanes. Borrowed words are viewed in place, as they are aligned to lanes in the index file.
+fn lanes(words: Cow<'_, Column>) -> Cow<'_, [Lane]> {
+    match words {
+        Cow::Borrowed(words) => {
+            // SAFETY: every bit pattern is a valid lane, and `align_to` only returns lanes which are correctly aligned.
+            let (prefix, lanes, suffix) = unsafe { words.align_to::<Lane>() };
+            match prefix.is_empty() && suffix.is_empty() {
+                true => Cow::Borrowed(lanes),
+                false => Cow::Owned(to_lanes(words)),
+            }
+        }
+        Cow::Owned(words) => Cow::Owned(to_lanes(&words)),
+    }
+}
+
+/// Lanes with the first `len` bits set.
+fn ones(len: usize) -> Vec<Lane> {
+    let mut words = vec![0; compress::dense_words(len)];
+    let full = len / u64::BITS as usize;
+    words[..full].fill(u64::MAX);
+    if let Some(word) = words.get_mut(full) {
+        *word = (1 << (len % u64::BITS as usize)) - 1;
+    }
+    to_lanes(&words)
+}
+
+/// Clear the bits of `acc` which are not set in `lanes`. Returns whether any bits remain.
+fn and_lanes(acc: &mut [Lane], lanes: &[Lane]) -> bool {
+    acc.iter_mut()
+        .zip(lanes)
+        .for_each(|(acc, lane)| acc.and_inplace(lane));
+    acc.iter().any(|lane| *lane != Lane::ZERO)
+}
+
+/// Clear the bits of `acc` which are set in `lanes`. Returns whether any bits remain.
+fn and_not_lanes(acc: &mut [Lane], lanes: &[Lane]) -> bool {
+    acc.iter_mut()
+        .zip(lanes)
+        .for_each(|(acc, lane)| acc.and_inplace(&!*lane));
+    acc.iter().any(|lane| *lane != Lane::ZERO)
+}
+
+/// Number of points for which two columns differ.
+fn hamming(a: &Column, b: &Column) -> usize {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a ^ b).count_ones() as usize)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{metric::Euclidean, BitPart};
+    use sisap_data::{colors::parse_colors, nasa::parse_nasa, parser::parse};
+    use std::fs;
+
+    use super::*;
+
+    pub(crate) const NASA: &str = include_str!("../sisap-data/src/nasa.ascii");
+    pub(crate) const COLORS: &str = include_str!("../sisap-data/src/colors.ascii");
+
+    fn test<T>(dataset: &[T], bitpart: &Disk<T>, query: T, threshold: f64)
+    where
+        for<'a> T: Metric + Send + Sync + 'a,
+    {
+        let res = bitpart.range_search(query.clone(), threshold).unwrap();
+
+        // Check all points within threshold
+        assert!(res
+            .iter()
+            .all(|(point, _)| point.distance(&query) <= threshold));
+
+        // Check results match up with linear search
+        let brute_force = dataset
+            .iter()
+            .map(|pt| pt.distance(&query))
+            .filter(|d| *d <= threshold)
+            .count();
+
+        assert_eq!(res.len(), brute_force);
+    }
+
+    #[test]
+    fn sisap_nasa_par() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_par/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .build_on_disk("/tmp/sisap_nasa_par/", Some(8192))
+            .unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        test(&nasa, &bitpart, query, threshold);
+        std::fs::remove_dir_all("/tmp/sisap_nasa_par/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_stats() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_stats/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .build_on_disk("/tmp/sisap_nasa_stats/", Some(8192))
+            .unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        let (res, stats) = bitpart
+            .range_search_with_stats(query.clone(), threshold)
+            .unwrap();
+
+        assert_eq!(
+            stats.must_be_in + stats.must_be_out + stats.undecided,
+            bitpart.zones()
+        );
+        assert_eq!(stats.distance_calls, stats.candidates);
+        assert!(stats.candidates >= res.len());
+        assert_eq!(
+            res.len(),
+            bitpart.range_search(query, threshold).unwrap().len()
+        );
+        std::fs::remove_dir_all("/tmp/sisap_nasa_stats/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_blocks() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_blocks/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        // Blocks which are not a whole number of words are padded separately. Blocks of a whole page are aligned to pages.
+        for block_size in [Some(1000), Some(32768), None] {
+            let bitpart = Builder::new(nasa.clone(), 40)
+                .build_on_disk("/tmp/sisap_nasa_blocks/", block_size)
+                .unwrap();
+            assert_eq!(bitpart.index.len(), bitpart.zones() * bitpart.n_blocks());
+            for query in [317, 1000, 20000] {
+                test(&nasa, &bitpart, nasa[query].clone(), 1.0);
+            }
+
+            if block_size == Some(32768) {
+                assert!((0..bitpart.index.len())
+                    .filter(|&idx| !bitpart.index.is_compressed(idx))
+                    .all(|idx| bitpart.index.segment(idx).as_ptr().align_offset(4096) == 0));
+            }
+            drop(bitpart);
+            std::fs::remove_dir_all("/tmp/sisap_nasa_blocks/").unwrap();
+        }
+    }
+
+    #[test]
+    fn sisap_colors_par() {
+        std::fs::remove_dir_all("/tmp/sisap_colors_par/").ok();
+        let colors = parse_colors(COLORS)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(colors.clone(), 40)
+            .build_on_disk("/tmp/sisap_colors_par/", Some(8192))
+            .unwrap();
+        let query = colors[70446].clone();
+        let threshold = 0.5;
+
+        test(&colors, &bitpart, query, threshold);
+        std::fs::remove_dir_all("/tmp/sisap_colors_par/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_knn() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_knn/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .build_on_disk("/tmp/sisap_nasa_knn/", Some(8192))
+            .unwrap();
+        let query = nasa[317].clone();
+
+        let res = bitpart.knn_search(query.clone(), 10).unwrap();
+
+        let mut brute_force = nasa
+            .iter()
+            .map(|pt| pt.distance(&query))
+            .collect::<Vec<_>>();
+        brute_force.sort_by(|a, b| a.total_cmp(b));
+        brute_force.truncate(10);
+
+        assert_eq!(
+            res.into_iter().map(|(_, d)| d).collect::<Vec<_>>(),
+            brute_force
+        );
+        std::fs::remove_dir_all("/tmp/sisap_nasa_knn/").unwrap();
+    }
+
+    #[test]
+    fn nearest_neighbour() {
+        std::fs::remove_dir_all("/tmp/nn/").ok();
+
+        let points = parse(&fs::read_to_string("data/100k_d20_flat.ascii").unwrap())
+            .unwrap()
+            .1
+             .1
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let nns: Vec<Vec<(usize, f64)>> =
+            serde_json::from_str(&fs::read_to_string("data/100k_d20_flat.json").unwrap()).unwrap();
+
+        let queries = points
+            .iter()
+            .cloned()
+            .zip(nns)
+            .map(|(pt, nn)| (pt, nn.last().unwrap().1))
+            .take(1000)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(points.clone(), 40)
+            .build_on_disk("/tmp/nn/", Some(8192))
+            .unwrap();
+
+        for (query, threshold) in queries {
+            test(&points, &bitpart, query, threshold);
+        }
+
+        std::fs::remove_dir_all("/tmp/nn/").unwrap();
+    }
+
+    #[test]
+    fn nearest_neighbour_batch() {
+        std::fs::remove_dir_all("/tmp/nn_batch/").ok();
+
+        let points = parse(&fs::read_to_string("data/100k_d20_flat.ascii").unwrap())
+            .unwrap()
+            .1
+             .1
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let nns: Vec<Vec<(usize, f64)>> =
+            serde_json::from_str(&fs::read_to_string("data/100k_d20_flat.json").unwrap()).unwrap();
+
+        let queries = points
+            .iter()
+            .cloned()
+            .zip(nns)
+            .map(|(pt, nn)| (pt, nn.last().unwrap().1))
+            .take(1000)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(points.clone(), 40)
+            .build_on_disk("/tmp/nn_batch/", Some(8192))
+            .unwrap();
+
+        let results = bitpart.range_search_batch_with_radii(&queries).unwrap();
+        assert_eq!(results.len(), queries.len());
+
+        for ((query, threshold), res) in queries.into_iter().zip(results) {
+            assert!(res
+                .iter()
+                .all(|(point, _)| point.distance(&query) <= threshold));
+
+            let brute_force = points
+                .iter()
+                .map(|pt| pt.distance(&query))
+                .filter(|d| *d <= threshold)
+                .count();
+            assert_eq!(res.len(), brute_force);
+        }
+
+        std::fs::remove_dir_all("/tmp/nn_batch/").unwrap();
+    }
+
+    #[test]
+    fn existing_dir() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let _ = fs::remove_dir_all("/tmp/existing_dir/");
+        fs::create_dir("/tmp/existing_dir/").unwrap();
+
+        let res = Builder::new(nasa, 40).try_build_on_disk("/tmp/existing_dir/", Some(512));
+        assert!(matches!(res, Err(BuildError::Io(_))));
+
+        fs::remove_dir_all("/tmp/existing_dir/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_open() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_open/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40)
+            .ref_point_selection(crate::RefPointSelection::Random { seed: 7 })
+            .build_on_disk("/tmp/sisap_nasa_open/", Some(512))
+            .unwrap();
+        bitpart.cull_to(100).unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        let opened = Disk::open("/tmp/sisap_nasa_open/", nasa.clone()).unwrap();
+        assert_eq!(opened.zones(), 100);
+        assert_eq!(
+            opened
+                .range_search_indices(query.clone(), threshold)
+                .unwrap(),
+            bitpart
+                .range_search_indices(query.clone(), threshold)
+                .unwrap()
+        );
+        test(&nasa, &opened, query, threshold);
+
+        assert!(matches!(
+            Disk::open("/tmp/sisap_nasa_open/", nasa[1..].to_vec()),
+            Err(DiskError::Manifest(PersistError::DatasetLength { .. }))
+        ));
+        assert!(matches!(
+            Disk::open("/tmp/sisap_nasa_open/missing/", nasa),
+            Err(DiskError::Io(_))
+        ));
+
+        std::fs::remove_dir_all("/tmp/sisap_nasa_open/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_insert() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_insert/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        // The last block is partly full before points are inserted.
+        let mut bitpart = Builder::new(nasa[..20000].to_vec(), 40)
+            .build_on_disk("/tmp/sisap_nasa_insert/", Some(1536))
+            .unwrap();
+        assert_eq!(
+            bitpart
+                .insert_batch(nasa[20000..nasa.len() - 100].to_vec())
+                .unwrap(),
+            20000..nasa.len() - 100
+        );
+        for (idx, pt) in nasa.iter().enumerate().skip(nasa.len() - 100) {
+            assert_eq!(bitpart.insert(pt.clone()).unwrap(), idx);
+        }
+        assert_eq!(bitpart.len(), nasa.len());
+        assert_eq!(bitpart.index.len(), bitpart.zones() * bitpart.n_blocks());
+        assert!(bitpart.verify().is_empty());
+
+        // Inserting one point at a time compacts the index file instead of letting it grow without bound.
+        assert!(!bitpart.index.is_sparse(alignment(1536)));
+
+        for query in [317, 20100, 31700] {
+            test(&nasa, &bitpart, nasa[query].clone(), 1.0);
+        }
+
+        let query = nasa[31700].clone();
+        let opened = Disk::open("/tmp/sisap_nasa_insert/", nasa.clone()).unwrap();
+        assert_eq!(
+            opened.range_search_indices(query.clone(), 1.0).unwrap(),
+            bitpart.range_search_indices(query, 1.0).unwrap()
+        );
+
+        std::fs::remove_dir_all("/tmp/sisap_nasa_insert/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_verify() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_verify/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .build_on_disk("/tmp/sisap_nasa_verify/", Some(512))
+            .unwrap();
+        assert!(bitpart.verify().is_empty());
+
+        // Flip a byte in the middle of the first dense segment, and overwrite the first toggle of the first run-length coded segment
+        // so that its runs are no longer sorted. The first segment starts on the first aligned boundary after the header and table
+        // offset.
+        let path = "/tmp/sisap_nasa_verify/index";
+        let mut bytes = fs::read(path).unwrap();
+        let first = (crate::persist::HEADER_LEN + 8).next_multiple_of(alignment(512) as usize);
+        let offset = |idx: usize| {
+            first + bitpart.index.segment(idx).as_ptr() as usize
+                - bitpart.index.segment(0).as_ptr() as usize
+        };
+        let dense = (0..bitpart.index.len())
+            .find(|&idx| !bitpart.index.is_compressed(idx))
+            .unwrap();
+        bytes[offset(dense) + bitpart.index.segment(dense).len() / 2] ^= 0xFF;
+        let runs = (0..bitpart.index.len())
+            .find(|&idx| bitpart.index.is_compressed(idx) && bitpart.index.segment(idx).len() >= 8)
+            .unwrap();
+        bytes[offset(runs)..offset(runs) + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let zones = [dense / bitpart.n_blocks(), runs / bitpart.n_blocks()];
+        drop(bitpart);
+        fs::write(path, &bytes).unwrap();
+
+        // Corrupt runs do not stop the directory from being opened, but are reported by `verify` and when they are read.
+        let mut opened = Disk::open("/tmp/sisap_nasa_verify/", nasa.clone()).unwrap();
+        let mut expected = zones.to_vec();
+        expected.sort();
+        expected.dedup();
+        assert_eq!(opened.verify(), expected);
+        assert!(matches!(
+            opened.cull_by_popcnt(1.0),
+            Err(DiskError::Manifest(PersistError::Corrupt))
+        ));
+        drop(opened);
+
+        // Truncating the file cuts off the table, which is detected on opening.
+        fs::write(path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(matches!(
+            Disk::open("/tmp/sisap_nasa_verify/", nasa),
+            Err(DiskError::Manifest(PersistError::Corrupt))
+        ));
+
+        std::fs::remove_dir_all("/tmp/sisap_nasa_verify/").unwrap();
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn zero_copy_columns() {
+        std::fs::remove_dir_all("/tmp/zero_copy_columns/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 10)
+            .build_on_disk("/tmp/zero_copy_columns/", Some(512))
+            .unwrap();
+
+        for idx in 0..bitpart.zones() {
+            for blk_idx in 0..bitpart.n_blocks() {
+                let segment = bitpart.segment_words(idx, blk_idx).unwrap();
+                assert_eq!(
+                    matches!(segment, Cow::Borrowed(_)),
+                    !bitpart
+                        .index
+                        .is_compressed(idx * bitpart.n_blocks() + blk_idx)
+                );
+                assert_eq!(
+                    segment.len(),
+                    compress::dense_words(bitpart.block_len(blk_idx))
+                );
+                if let Cow::Borrowed(words) = segment {
+                    assert_eq!(words.as_ptr() as usize % container::ALIGN as usize, 0);
+                }
+            }
+        }
+
+        std::fs::remove_dir_all("/tmp/zero_copy_columns/").unwrap();
+    }
+
+    #[test]
+    fn zero_copy_candidates() {
+        std::fs::remove_dir_all("/tmp/zero_copy_candidates/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 10)
+            .build_on_disk("/tmp/zero_copy_candidates/", Some(512))
+            .unwrap();
+
+        for query in nasa.iter().step_by(1000) {
+            let (ins, outs) = bitpart.classify(query, 0.5);
+            let candidates = bitpart
+                .blocks()
+                .map(|(blk_idx, from, len)| {
+                    let segments = ins
+                        .iter()
+                        .chain(&outs)
+                        .map(|&idx| bitpart.segment_words(idx, blk_idx).unwrap())
+                        .collect::<Vec<_>>();
+                    // Dense segments reach the filter straight from the memory map.
+                    assert!(segments.iter().zip(ins.iter().chain(&outs)).all(
+                        |(segment, &idx)| matches!(
+                            (segment, lanes(Cow::Borrowed(segment))),
+                            (Cow::Borrowed(_), Cow::Borrowed(_))
+                        ) || bitpart
+                            .index
+                            .is_compressed(idx * bitpart.n_blocks() + blk_idx)
+                    ));
+                    Disk::<Euclidean<Vec<f64>>>::block_candidates(
+                        from,
+                        len,
+                        segments[..ins.len()]
+                            .iter()
+                            .map(|s| Ok(Cow::Borrowed(&**s))),
+                        segments[ins.len()..]
+                            .iter()
+                            .map(|s| Ok(Cow::Borrowed(&**s))),
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+                .concat();
+
+            let expected = (0..nasa.len())
+                .filter(|&idx| {
+                    ins.iter()
+                        .all(|&ez| bitpart.exclusions[ez].is_in(&nasa[idx]))
+                        && !outs
+                            .iter()
+                            .any(|&ez| bitpart.exclusions[ez].is_in(&nasa[idx]))
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(candidates, expected);
+
+            let (_, stats) = bitpart.search_with_stats(query, 0.5).unwrap();
+            assert_eq!(stats.candidates, expected.len());
+        }
+
+        std::fs::remove_dir_all("/tmp/zero_copy_candidates/").unwrap();
+    }
+
+    #[test]
+    fn compressed_columns() {
+        std::fs::remove_dir_all("/tmp/compressed_columns/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        // Sorting the dataset along one axis makes sheets along that axis split it into a handful of runs.
+        let mut sorted = nasa.clone();
+        sorted.sort_by(|a, b| a[0].total_cmp(&b[0]));
+
+        let bitpart = Builder::new(sorted.clone(), 40)
+            .build_on_disk("/tmp/compressed_columns/", Some(512))
+            .unwrap();
+        let compressed = (0..bitpart.index.len())
+            .filter(|&idx| bitpart.index.is_compressed(idx))
+            .count();
+        assert!(compressed > 0 && compressed < bitpart.index.len());
+
+        let opened = Disk::open("/tmp/compressed_columns/", sorted.clone()).unwrap();
+        assert!(opened.verify().is_empty());
+        for query in [317, 1000, 20000] {
+            test(&sorted, &opened, sorted[query].clone(), 1.0);
+        }
+
+        std::fs::remove_dir_all("/tmp/compressed_columns/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_streaming() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_streaming/").ok();
+        std::fs::remove_dir_all("/tmp/sisap_nasa_streaming_memory/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(|pt| Euclidean::new(pt.0))
+            .collect::<Vec<_>>();
+
+        let file = fs::File::open("sisap-data/src/nasa.ascii").unwrap();
+        let (_, vectors) = sisap_data::parser::read(std::io::BufReader::new(file)).unwrap();
+        let mut points =
+            vectors.map(|v| v.map(|v| Euclidean::new(<[f64; 20]>::try_from(v).unwrap())));
+        let sample = points
+            .by_ref()
+            .take(5000)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let bitpart = Builder::new(sample, 40)
+            .build_on_disk_streaming(points, "/tmp/sisap_nasa_streaming/", Some(512), 1000)
+            .unwrap();
+        assert_eq!(bitpart.len(), nasa.len());
+        assert!(matches!(bitpart.dataset, Points::Mapped { .. }));
+        for query in [317, 5000, 20000] {
+            test(&nasa, &bitpart, nasa[query].clone(), 1.0);
+        }
+
+        // Zones only depend on the first points, so the columns match those built in memory.
+        let memory = Builder::new(nasa.clone(), 40)
+            .build_on_disk("/tmp/sisap_nasa_streaming_memory/", Some(512))
+            .unwrap();
+        assert_eq!(bitpart.zones(), memory.zones());
+        assert!((0..bitpart.zones())
+            .all(|idx| bitpart.column(idx).unwrap() == memory.column(idx).unwrap()));
+        drop(bitpart);
+
+        let dir = fs::read_dir("/tmp/sisap_nasa_streaming/")
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(dir.len(), 2);
+        let opened = Disk::open_mapped("/tmp/sisap_nasa_streaming/").unwrap();
+        test(&nasa, &opened, nasa[317].clone(), 1.0);
+
+        std::fs::remove_dir_all("/tmp/sisap_nasa_streaming/").unwrap();
+        std::fs::remove_dir_all("/tmp/sisap_nasa_streaming_memory/").unwrap();
+    }
+
+    #[test]
+    fn streaming_error() {
+        std::fs::remove_dir_all("/tmp/streaming_error/").ok();
+        let points = (0..1000)
+            .map(|i| Euclidean::new([i as f64, 0.0]))
+            .collect::<Vec<_>>();
+
+        let rest = points.iter().skip(100).enumerate().map(|(i, pt)| match i {
+            500 => Err(std::io::Error::from(std::io::ErrorKind::InvalidData)),
+            _ => Ok(pt.clone()),
+        });
+        assert!(matches!(
+            Builder::new(points[..100].to_vec(), 10).build_on_disk_streaming(
+                rest,
+                "/tmp/streaming_error/",
+                None,
+                64
+            ),
+            Err(DiskError::Io(_))
+        ));
+        assert!(!Path::new("/tmp/streaming_error/").join(CHUNKS).exists());
+
+        std::fs::remove_dir_all("/tmp/streaming_error/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_mapped() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_mapped/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(|pt| Euclidean::new(pt.0))
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .build_on_disk_mapped("/tmp/sisap_nasa_mapped/", Some(512))
+            .unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        assert!(matches!(
+            bitpart.range_search_ref(query.clone(), threshold),
+            Err(DiskError::PointsOnDisk)
+        ));
+        test(&nasa, &bitpart, query.clone(), threshold);
+
+        let opened = Disk::<Euclidean<[f64; 20]>>::open_mapped("/tmp/sisap_nasa_mapped/").unwrap();
+        assert_eq!(opened.len(), nasa.len());
+        assert_eq!(
+            opened
+                .range_search_indices(query.clone(), threshold)
+                .unwrap(),
+            bitpart
+                .range_search_indices(query.clone(), threshold)
+                .unwrap()
+        );
+        assert_eq!(
+            opened
+                .knn_search(query.clone(), 10)
+                .unwrap()
+                .into_iter()
+                .map(|(pt, dist)| (*pt, dist))
+                .collect::<Vec<_>>(),
+            bitpart
+                .knn_search(query.clone(), 10)
+                .unwrap()
+                .into_iter()
+                .map(|(pt, dist)| (*pt, dist))
+                .collect::<Vec<_>>()
+        );
+
+        // Points of another fixed dimension are rejected, and points of any dimension are accepted.
+        let Err(DiskError::Dimension { expected, found }) =
+            Disk::<Euclidean<[f64; 10]>>::open_mapped("/tmp/sisap_nasa_mapped/")
+        else {
+            panic!("points of 20 dimensions were opened as 10");
+        };
+        assert_eq!((expected, found), (10, 20));
+        let opened = Disk::<Euclidean<Vec<f64>>>::open_mapped("/tmp/sisap_nasa_mapped/").unwrap();
+        assert_eq!(
+            opened
+                .range_search_indices(Euclidean::new(query.to_vec()), threshold)
+                .unwrap(),
+            bitpart.range_search_indices(query, threshold).unwrap()
+        );
+
+        std::fs::remove_dir_all("/tmp/sisap_nasa_mapped/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_mapped_insert() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_mapped_insert/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(|pt| Euclidean::new(pt.0))
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa[..20000].to_vec(), 40)
+            .build_on_disk_mapped("/tmp/sisap_nasa_mapped_insert/", Some(512))
+            .unwrap();
+        bitpart.insert_batch(nasa[20000..].to_vec()).unwrap();
+        let query = nasa[31700].clone();
+        test(&nasa, &bitpart, query.clone(), 1.0);
+
+        // Inserted points are appended to the point store.
+        let opened =
+            Disk::<Euclidean<[f64; 20]>>::open_mapped("/tmp/sisap_nasa_mapped_insert/").unwrap();
+        assert_eq!(opened.len(), nasa.len());
+        assert_eq!(
+            opened.range_search_indices(query.clone(), 1.0).unwrap(),
+            bitpart.range_search_indices(query, 1.0).unwrap()
+        );
+        drop(opened);
+
+        // If the points cannot be stored, the index is left as it was.
+        let store = Path::new("/tmp/sisap_nasa_mapped_insert/").join(POINTS);
+        fs::remove_file(&store).unwrap();
+        fs::create_dir(&store).unwrap();
+        assert!(matches!(
+            bitpart.insert_batch(nasa[..10].to_vec()),
+            Err(DiskError::Io(_))
+        ));
+        assert_eq!(bitpart.len(), nasa.len());
+        let opened = Disk::open("/tmp/sisap_nasa_mapped_insert/", nasa.clone()).unwrap();
+        assert_eq!(opened.len(), nasa.len());
+
+        std::fs::remove_dir_all("/tmp/sisap_nasa_mapped_insert/").unwrap();
+    }
+
+    #[test]
+    fn mapped_dimension() {
+        std::fs::remove_dir_all("/tmp/mapped_dimension/").ok();
+        let points = (0..10)
+            .map(|i| Euclidean::new(vec![i as f64; 1 + i % 2]))
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            Builder::new(points, 2).build_on_disk_mapped("/tmp/mapped_dimension/", None),
+            Err(DiskError::Dimension {
+                expected: 1,
+                found: 2
+            })
+        ));
+        std::fs::remove_dir_all("/tmp/mapped_dimension/").unwrap();
+
+        let points = (0..10)
+            .map(|i| Euclidean::new(vec![i as f64]))
+            .collect::<Vec<_>>();
+        let mut bitpart = Builder::new(points, 2)
+            .build_on_disk_mapped("/tmp/mapped_dimension/", None)
+            .unwrap();
+        assert!(matches!(
+            bitpart.insert_batch([Euclidean::new(vec![10.0]), Euclidean::new(vec![11.0; 2])]),
+            Err(DiskError::Dimension {
+                expected: 1,
+                found: 2
+            })
+        ));
+        assert_eq!(bitpart.len(), 10);
+
+        std::fs::remove_dir_all("/tmp/mapped_dimension/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_cull() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_cull/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40)
+            .build_on_disk("/tmp/sisap_nasa_cull/", Some(8192))
+            .unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        bitpart.cull_by_popcnt(0.95).unwrap();
+        bitpart.cull_by_similarity(0.95).unwrap();
+        assert!(bitpart.zones() < 200 + 780);
+
+        // The index is rewritten in place, with one segment per zone and block.
+        let files = fs::read_dir("/tmp/sisap_nasa_cull/")
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(files, [INDEX]);
+        assert_eq!(bitpart.index.len(), bitpart.zones() * bitpart.n_blocks());
+
+        test(&nasa, &bitpart, query, threshold);
+        std::fs::remove_dir_all("/tmp/sisap_nasa_cull/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_workload() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_workload/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40)
+            .build_on_disk("/tmp/sisap_nasa_workload/", Some(8192))
+            .unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        bitpart
+            .select_zones_for_workload(&nasa[1000..1010], threshold, 50)
+            .unwrap();
+        assert_eq!(bitpart.zones(), 50);
+        assert_eq!(
+            fs::read_dir("/tmp/sisap_nasa_workload/").unwrap().count(),
+            1
+        );
+        assert_eq!(bitpart.index.len(), 50 * bitpart.n_blocks());
+
+        test(&nasa, &bitpart, query, threshold);
+        std::fs::remove_dir_all("/tmp/sisap_nasa_workload/").unwrap();
+    }
+
+    #[test]
+    fn sisap_nasa_cull_to() {
+        std::fs::remove_dir_all("/tmp/sisap_nasa_cull_to/").ok();
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40)
+            .build_on_disk("/tmp/sisap_nasa_cull_to/", Some(8192))
+            .unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        bitpart.cull_to(100).unwrap();
+        assert_eq!(bitpart.zones(), 100);
+        assert_eq!(fs::read_dir("/tmp/sisap_nasa_cull_to/").unwrap().count(), 1);
+        assert_eq!(bitpart.index.len(), 100 * bitpart.n_blocks());
+
+        test(&nasa, &bitpart, query, threshold);
+        std::fs::remove_dir_all("/tmp/sisap_nasa_cull_to/").unwrap();
+    }
+}
+
+impl<T> Builder<T>
+where
+    for<'a> T: Metric + Send + Sync + 'a,
+{
+    /// Construct a [`Disk`](crate::Disk).
+    ///
+    /// `path` should be a path for the directory in which partitioning data will be stored.
+    /// This function uses [`create_dir`](std::fs::create_dir) to create the directory, *not* [`create_dir_all`](std::fs::create_dir_all).
+    ///
+    /// `block_size` is the number of points in each block of partitioning data, which is stored and read separately.
+    /// If it is `None`, the whole dataset is a single block.
+    ///
+    /// # Panics
+    /// This function will panic if exclusion zones were added with [`with_exclusions`](crate::Builder::with_exclusions), as they are not
+    /// guaranteed to be `Send` and `Sync`. Use [`with_exclusions_sync`](crate::Builder::with_exclusions_sync) instead.
+    ///
+    /// More generally, this function will panic if [`try_build_on_disk`](crate::Builder::try_build_on_disk) would return an error
+    /// which is not a [`DiskError`].
+    pub fn build_on_disk<'a, P>(
+        self,
+        path: P,
+        block_size: Option<usize>,
+    ) -> Result<Disk<'a, T>, DiskError>
+    where
+        P: AsRef<std::path::Path> + 'a,
+    {
+        match self.try_build_on_disk(path, block_size) {
+            Ok(disk) => Ok(disk),
+            Err(BuildError::Io(e)) => Err(DiskError::Io(e)),
+            Err(BuildError::Disk(e)) => Err(e),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Construct a [`Disk`](crate::Disk), returning an error instead of panicking.
+    ///
+    /// See [`build_on_disk`](crate::Builder::build_on_disk) for details.
+    pub fn try_build_on_disk<'a, P>(
+        self,
+        path: P,
+        block_size: Option<usize>,
+    ) -> Result<Disk<'a, T>, BuildError>
+    where
+        P: AsRef<std::path::Path> + 'a,
+    {
+        if block_size == Some(0) {
+            return Err(BuildError::ZeroBlockSize);
+        }
+        if self.has_unsync_exclusions() {
+            return Err(BuildError::UnsyncExclusions);
+        }
+        self.validate()?;
+        std::fs::create_dir(&path)?;
+        Disk::setup(self, path, block_size)
+    }
+}
+
+impl<T> Builder<T>
+where
+    for<'a> T: Metric + FixedDimension + Send + Sync + 'a,
+{
+    /// Construct a [`Disk`](crate::Disk) which also stores the dataset on disk.
+    ///
+    /// Once the data structure has been built, the points are written to a file in `path` and memory-mapped. Only the points which
+    /// are candidates for a query are read, so the dataset does not need to fit in memory after construction.
+    /// [`range_search_ref`](crate::BitPart::range_search_ref) returns [`DiskError::PointsOnDisk`], as points are not held in memory.
+    ///
+    /// See [`build_on_disk`](crate::Builder::build_on_disk) for details.
+    pub fn build_on_disk_mapped<'a, P>(
+        self,
+        path: P,
+        block_size: Option<usize>,
+    ) -> Result<Disk<'a, T>, DiskError>
+    where
+        P: AsRef<std::path::Path> + 'a,
+    {
+        match self.try_build_on_disk_mapped(path, block_size) {
+            Ok(disk) => Ok(disk),
+            Err(BuildError::Io(e)) => Err(DiskError::Io(e)),
+            Err(BuildError::Disk(e)) => Err(e),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Construct a [`Disk`](crate::Disk) which also stores the dataset on disk, returning an error instead of panicking.
+    ///
+    /// See [`build_on_disk_mapped`](crate::Builder::build_on_disk_mapped) for details.
+    pub fn try_build_on_disk_mapped<'a, P>(
+        self,
+        path: P,
+        block_size: Option<usize>,
+    ) -> Result<Disk<'a, T>, BuildError>
+    where
+        P: AsRef<std::path::Path> + 'a,
+    {
+        let mut disk = self.try_build_on_disk(path, block_size)?;
+        disk.store_points()?;
+        Ok(disk)
+    }
+
+    /// Construct a [`Disk`](crate::Disk) for a dataset which does not fit in memory, reading it in chunks of `chunk_size` points.
+    ///
+    /// The dataset of the data structure is the builder's dataset followed by the points of `rest`. Only the builder's dataset is
+    /// used to choose reference points and to [calibrate](crate::Builder::calibrate) or [balance](crate::Builder::balance_sheets)
+    /// zones, so it should be a representative sample. The points of `rest` are read `chunk_size` at a time (rounded up to a
+    /// multiple of 256): each chunk's partitioning data is written to a temporary file in `path`, and its points are appended
+    /// to the point store, as with [`build_on_disk_mapped`](crate::Builder::build_on_disk_mapped). Once every point has
+    /// been read, columns are assembled from the temporary file a few at a time.
+    ///
+    /// `rest` yields results so that points can be read straight from a file, such as with
+    /// `sisap_data::parser::read`. Reading stops at the first error, which is returned.
+    /// Infallible iterators can be wrapped with `.map(Ok::<_, DiskError>)`.
+    ///
+    /// See [`build_on_disk`](crate::Builder::build_on_disk) for details.
+    pub fn build_on_disk_streaming<'a, P, I, E>(
+        self,
+        rest: I,
+        path: P,
+        block_size: Option<usize>,
+        chunk_size: usize,
+    ) -> Result<Disk<'a, T>, DiskError>
+    where
+        P: AsRef<std::path::Path> + 'a,
+        I: IntoIterator<Item = Result<T, E>>,
+        E: Into<DiskError>,
+    {
+        match self.try_build_on_disk_streaming(rest, path, block_size, chunk_size) {
+            Ok(disk) => Ok(disk),
+            Err(BuildError::Io(e)) => Err(DiskError::Io(e)),
+            Err(BuildError::Disk(e)) => Err(e),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Construct a [`Disk`](crate::Disk) for a dataset which does not fit in memory, returning an error instead of panicking.
+    ///
+    /// See [`build_on_disk_streaming`](crate::Builder::build_on_disk_streaming) for details.
+    pub fn try_build_on_disk_streaming<'a, P, I, E>(
+        self,
+        rest: I,
+        path: P,
+        block_size: Option<usize>,
+        chunk_size: usize,
+    ) -> Result<Disk<'a, T>, BuildError>
+    where
+        P: AsRef<std::path::Path> + 'a,
+        I: IntoIterator<Item = Result<T, E>>,
+        E: Into<DiskError>,
+    {
+        if block_size == Some(0) {
+            return Err(BuildError::ZeroBlockSize);
+        }
+        if self.has_unsync_exclusions() {
+            return Err(BuildError::UnsyncExclusions);
+        }
+        self.validate()?;
+        std::fs::create_dir(&path)?;
+        Disk::stream(self, rest, path, block_size, chunk_size)
     }
 }
 
@@ -338,10 +2054,28 @@ where
 #[derive(Debug, Error)]
 pub enum DiskError {
     /// Generic IO error. This means either the memory map could not be opened,
-    /// or a bitvector's file could not be accessed.
+    /// or the index file could not be accessed.
     #[error("io error")]
     Io(#[from] std::io::Error),
-    /// Could not (de)serialize a bitvector.
-    #[error("could not (de)serialize bitvector")]
+    /// Could not serialize the table of the index file.
+    #[error("could not serialize table")]
     Serde(#[from] bincode::Error),
+    /// Could not read or write the header or table of the index file, or the table does not match the dataset.
+    #[error("manifest error")]
+    Manifest(#[from] PersistError),
+    /// Points given to the point store do not all have the same number of dimensions, or the point store was
+    /// [opened](crate::Disk::open_mapped) with a point type of a different number of dimensions.
+    #[error("expected points with {expected} dimensions, found {found}")]
+    Dimension {
+        /// Number of dimensions of the first point, of the points already in the point store, or of the point type it was opened as.
+        expected: usize,
+        /// Number of dimensions of the offending point, or of the points in the point store that was opened.
+        found: usize,
+    },
+    /// References to points were requested, but the dataset is stored on disk.
+    #[error("dataset is stored on disk")]
+    PointsOnDisk,
+    /// Exclusion zones could not be culled.
+    #[error("could not cull exclusion zones")]
+    Cull(#[from] CullError),
 }
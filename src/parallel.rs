@@ -1,12 +1,22 @@
-use crate::builder::Builder;
-use crate::exclusions::{BallExclusion, ExclusionSync, SheetExclusion};
+use crate::builder::{BuildError, Builder, Params};
+use crate::compress::{Column, Runs};
+use crate::cull::{self, CullError, Cullable};
+use crate::exclusions::{ExclusionSync, ZoneDef};
 use crate::metric::Metric;
-use crate::BitPart;
+#[cfg(feature = "serde")]
+use crate::persist::{self, Kind, PersistError};
+use crate::{BitPart, QueryStats};
 
 use bitvec_simd::BitVec;
-use itertools::{Either, Itertools};
+use itertools::Either;
 use rayon::prelude::*;
-use std::collections::HashSet;
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+use std::ops::Range;
+#[cfg(feature = "serde")]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
 
 /// Parallel BitPart.
@@ -24,12 +34,19 @@ use thiserror::Error;
 ///
 /// Explicit SIMD optimisations for bitvector operations are provided by [`bitvec_simd`](bitvec_simd).
 ///
+/// Within each block, the partitioning data of a zone is run-length coded instead of stored as a dense bitset when that is smaller,
+/// which is often the case for zones containing almost all or almost none of the block. Such columns are decoded when queried.
+///
 /// See [`build_parallel`](crate::builder::Builder::build_parallel) for configuration.
 pub struct Parallel<'a, T> {
     dataset: Vec<T>,
     exclusions: Vec<Box<dyn ExclusionSync<T> + 'a>>,
-    bitset: Vec<Vec<BitVec>>,
+    bitset: Vec<Vec<Column>>,
     block_size: usize,
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    ref_points: Vec<T>,
+    zones: Vec<ZoneDef>,
+    params: Params,
 }
 
 impl<T> BitPart<T> for Parallel<'_, T>
@@ -39,59 +56,56 @@ where
     type Error = ParallelError;
 
     fn range_search(&self, point: T, threshold: f64) -> Result<Vec<(T, f64)>, ParallelError> {
-        if self.exclusions.is_empty() {
-            return Err(ParallelError::NoZones);
-        }
-
-        let (ins, outs): (Vec<usize>, Vec<usize>) = self
-            .exclusions
-            .par_iter()
-            .enumerate()
-            .filter_map(|(idx, ez)| {
-                if ez.must_be_in(&point, threshold) {
-                    Some(Either::Left(idx))
-                } else if ez.must_be_out(&point, threshold) {
-                    Some(Either::Right(idx))
-                } else {
-                    None
-                }
-            })
-            .partition_map(|x| x);
-
-        let res = self
-            .bitset
-            .par_iter()
-            .enumerate()
-            .flat_map(|(block_idx, bitvecs)| {
-                // SAFETY: Queries can only be made if there are still exclusion zones left.
-                let len = bitvecs[0].len();
+        Ok(self
+            .search(&point, threshold)?
+            .into_par_iter()
+            .map(|(idx, dist)| (self.dataset[idx].clone(), dist))
+            .collect())
+    }
 
-                let ands = ins
-                    .iter()
-                    .map(|idx| bitvecs.get(*idx).unwrap())
-                    .fold(BitVec::ones(len), |acc, v| acc & v); // TODO: fold or reduce?
+    fn range_search_indices(
+        &self,
+        point: T,
+        threshold: f64,
+    ) -> Result<Vec<(usize, f64)>, Self::Error> {
+        self.search(&point, threshold)
+    }
 
-                let nots = !outs
-                    .iter()
-                    .map(|idx| bitvecs.get(*idx).unwrap())
-                    .fold(BitVec::zeros(len), |acc, v| acc | v);
+    fn range_search_ref(&self, point: T, threshold: f64) -> Result<Vec<(&T, f64)>, Self::Error> {
+        Ok(self
+            .search(&point, threshold)?
+            .into_par_iter()
+            .map(|(idx, dist)| (&self.dataset[idx], dist))
+            .collect())
+    }
 
-                let res = ands & nots;
+    fn range_search_with_stats(
+        &self,
+        point: T,
+        threshold: f64,
+    ) -> Result<(Vec<(T, f64)>, QueryStats), ParallelError> {
+        let (res, stats) = self.search_with_stats(&point, threshold)?;
+
+        Ok((
+            res.into_par_iter()
+                .map(|(idx, dist)| (self.dataset[idx].clone(), dist))
+                .collect(),
+            stats,
+        ))
+    }
 
-                res.into_usizes()
-                    .into_iter()
-                    .map(|internal_idx| {
-                        self.dataset
-                            .get((block_idx * self.block_size) + internal_idx)
-                            .unwrap()
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .map(|pt| (pt.clone(), point.distance(pt)))
-            .filter(|(_, d)| *d <= threshold)
-            .collect::<Vec<_>>();
+    fn knn_search(&self, point: T, k: usize) -> Result<Vec<(T, f64)>, ParallelError> {
+        let res = crate::expanding_knn(
+            self.dataset.len(),
+            k,
+            self.params.radius_increment,
+            |radius| self.search(&point, radius),
+        )?;
 
-        Ok(res)
+        Ok(res
+            .into_iter()
+            .map(|(idx, dist)| (self.dataset[idx].clone(), dist))
+            .collect())
     }
 
     fn len(&self) -> usize {
@@ -112,53 +126,329 @@ where
     T: Metric + Send + Sync,
     dyn ExclusionSync<T>: 'a,
 {
-    pub(crate) fn setup(builder: Builder<T>, block_size: Option<usize>) -> Self {
+    pub(crate) fn setup(
+        builder: Builder<T>,
+        block_size: Option<usize>,
+    ) -> Result<Self, BuildError> {
         let block_size = block_size.unwrap_or(builder.dataset.len());
-        // TODO: actually randomise this
-        let ref_points = &builder.dataset[0..(builder.ref_points as usize)];
-        let mut exclusions = Self::ball_exclusions(&builder, ref_points);
-        exclusions.extend(Self::sheet_exclusions(&builder, ref_points));
-        let bitset = Self::make_bitset(block_size, &builder, &exclusions);
-        Self {
+        let parallel = Self::make(&builder, block_size)?;
+        Ok(Self {
             dataset: builder.dataset,
+            ..parallel
+        })
+    }
+
+    /// Insert `point` into the dataset, returning its index.
+    ///
+    /// See [`insert_batch`](Parallel::insert_batch) for details.
+    pub fn insert(&mut self, point: T) -> usize {
+        self.insert_batch([point]).start
+    }
+
+    /// Insert `points` into the dataset, returning the range of their indices.
+    ///
+    /// Each point is checked against the existing exclusion zones, which are not redefined. If the dataset grows to look very
+    /// different from the points the data structure was built from, [rebuilding](Parallel::rebuild) it gives more effective zones.
+    ///
+    /// Points fill the last block before new blocks are opened. Run-length coded columns which grow too long are stored densely.
+    pub fn insert_batch(&mut self, points: impl IntoIterator<Item = T>) -> Range<usize> {
+        let start = self.dataset.len();
+        self.dataset.extend(points);
+
+        let mut from = start;
+        while from < self.dataset.len() {
+            let blk_idx = from / self.block_size;
+            let to = self.dataset.len().min((blk_idx + 1) * self.block_size);
+            let points = &self.dataset[from..to];
+            if blk_idx == self.bitset.len() {
+                self.bitset.push(
+                    self.exclusions
+                        .par_iter()
+                        .map(|ez| Column::new(points.iter().map(|pt| ez.is_in(pt)).collect()))
+                        .collect(),
+                );
+            } else {
+                self.bitset[blk_idx]
+                    .par_iter_mut()
+                    .zip(&self.exclusions)
+                    .for_each(|(column, ez)| column.extend(points.iter().map(|pt| ez.is_in(pt))));
+            }
+            from = to;
+        }
+
+        start..self.dataset.len()
+    }
+
+    /// Rebuild the exclusion zones and partitioning data of the data structure in place, without re-ingesting the dataset.
+    ///
+    /// This is useful after zones have been [culled](crate::Cullable), or to try a different configuration. `configure` is given a
+    /// [`Builder`](crate::Builder) which uses `ref_points` reference points, and should return it with any other settings applied.
+    /// The existing dataset is then lent to the builder. The block size is kept.
+    ///
+    /// If an error is returned, or if `configure` or building panics, the data structure is left unchanged.
+    pub fn rebuild(
+        &mut self,
+        ref_points: u64,
+        configure: impl FnOnce(Builder<T>) -> Builder<T>,
+    ) -> Result<(), BuildError> {
+        let mut builder = configure(Builder::unchecked(vec![], ref_points));
+        if builder.has_unsync_exclusions() {
+            return Err(BuildError::UnsyncExclusions);
+        }
+
+        builder.dataset = std::mem::take(&mut self.dataset);
+        let lent = Lent {
+            home: &mut self.dataset,
+            builder,
+        };
+        let rebuilt = Self::make(&lent.builder, self.block_size);
+        drop(lent);
+
+        let rebuilt = rebuilt?;
+        *self = Self {
+            dataset: std::mem::take(&mut self.dataset),
+            ..rebuilt
+        };
+        Ok(())
+    }
+
+    /// Create the exclusion zones described by `builder` along with their partitioning data, without taking its dataset.
+    fn make(builder: &Builder<T>, block_size: usize) -> Result<Self, BuildError> {
+        builder.validate()?;
+        let ref_points = builder.select_ref_points();
+        let zones = builder.zone_defs(&ref_points)?;
+        let mut exclusions = Self::builtin_exclusions(&ref_points, &zones);
+        exclusions.extend(
+            builder
+                .exclusions_sync
+                .iter()
+                .map(|ez| Box::new(Arc::clone(ez)) as Box<dyn ExclusionSync<T>>),
+        );
+        let bitset = Self::make_bitset(block_size, builder, &exclusions);
+        Ok(Self {
+            dataset: vec![],
             bitset,
             exclusions,
+            ref_points,
+            zones,
             block_size,
-        }
+            params: builder.params(),
+        })
     }
 
-    fn ball_exclusions(
-        builder: &Builder<T>,
-        ref_points: &[T],
-    ) -> Vec<Box<dyn ExclusionSync<T> + 'a>> {
-        let radii = [
-            builder.mean_distance - 2.0 * builder.radius_increment,
-            builder.mean_distance - builder.radius_increment,
-            builder.mean_distance,
-            builder.mean_distance + builder.radius_increment,
-            builder.mean_distance + 2.0 * builder.radius_increment,
-        ];
-
-        ref_points
-            .iter()
-            .cartesian_product(radii.into_iter())
-            .map(|(point, radius)| {
-                Box::new(BallExclusion::new(point.clone(), radius)) as Box<dyn ExclusionSync<T>>
+    /// Perform a range search for each point in `queries`, using the same radius `threshold` for all of them.
+    ///
+    /// Returns one vector of results per query, in the same order as `queries`.
+    /// See [`range_search_batch_with_radii`](crate::Parallel::range_search_batch_with_radii) for details.
+    pub fn range_search_batch(
+        &self,
+        queries: &[T],
+        threshold: f64,
+    ) -> Result<Vec<Vec<(T, f64)>>, ParallelError> {
+        let queries = queries.iter().map(|q| (q, threshold)).collect::<Vec<_>>();
+        Ok(self.resolve_batch(self.search_batch(&queries)?))
+    }
+
+    /// Perform a range search for each `(point, threshold)` pair in `queries`.
+    ///
+    /// Returns one vector of results per query, in the same order as `queries`.
+    ///
+    /// This is faster than calling [`range_search`](crate::BitPart::range_search) in a loop. Every query is filtered against every
+    /// block of partitioning data in parallel, instead of the queries being run one at a time, and the candidates in each block are
+    /// verified as soon as it is filtered.
+    pub fn range_search_batch_with_radii(
+        &self,
+        queries: &[(T, f64)],
+    ) -> Result<Vec<Vec<(T, f64)>>, ParallelError> {
+        let queries = queries.iter().map(|(q, t)| (q, *t)).collect::<Vec<_>>();
+        Ok(self.resolve_batch(self.search_batch(&queries)?))
+    }
+
+    fn resolve_batch(&self, results: Vec<Vec<(usize, f64)>>) -> Vec<Vec<(T, f64)>> {
+        results
+            .into_par_iter()
+            .map(|res| {
+                res.into_iter()
+                    .map(|(idx, dist)| (self.dataset[idx].clone(), dist))
+                    .collect()
             })
             .collect()
     }
 
-    fn sheet_exclusions(
-        _builder: &Builder<T>,
+    /// Range search returning the indices of matching points, along with their distance from `point`.
+    fn search(&self, point: &T, threshold: f64) -> Result<Vec<(usize, f64)>, ParallelError> {
+        Ok(self.search_with_stats(point, threshold)?.0)
+    }
+
+    /// Range search returning the indices of matching points, along with statistics about the query.
+    fn search_with_stats(
+        &self,
+        point: &T,
+        threshold: f64,
+    ) -> Result<(Vec<(usize, f64)>, QueryStats), ParallelError> {
+        let start = Instant::now();
+        let (ins, outs) = self.classify(point, threshold);
+        let exclusion_time = start.elapsed();
+
+        let start = Instant::now();
+        let candidates = self
+            .bitset
+            .par_iter()
+            .enumerate()
+            .flat_map(|(block_idx, bitvecs)| self.block_candidates(block_idx, bitvecs, &ins, &outs))
+            .collect::<Vec<_>>();
+        let filter_time = start.elapsed();
+
+        let start = Instant::now();
+        let res = candidates
+            .par_iter()
+            .map(|&idx| (idx, point.distance(self.dataset.get(idx).unwrap())))
+            .filter(|(_, d)| *d <= threshold)
+            .collect::<Vec<_>>();
+        let verify_time = start.elapsed();
+
+        let stats = QueryStats {
+            must_be_in: ins.len(),
+            must_be_out: outs.len(),
+            undecided: self.exclusions.len() - ins.len() - outs.len(),
+            candidates: candidates.len(),
+            distance_calls: candidates.len(),
+            exclusion_time,
+            filter_time,
+            verify_time,
+        };
+
+        Ok((res, stats))
+    }
+
+    /// Range search over many queries at once, returning the indices of matching points for each query.
+    fn search_batch(&self, queries: &[(&T, f64)]) -> Result<Vec<Vec<(usize, f64)>>, ParallelError> {
+        let zones = queries
+            .par_iter()
+            .map(|(point, threshold)| self.classify(point, *threshold))
+            .collect::<Vec<_>>();
+
+        // Each block is filtered for every query and its candidates verified straight away, so only matching points are kept.
+        // Blocks and the queries within each block are both processed in parallel.
+        let found = self
+            .bitset
+            .par_iter()
+            .enumerate()
+            .map(|(block_idx, bitvecs)| {
+                zones
+                    .par_iter()
+                    .zip(queries)
+                    .map(|((ins, outs), (point, threshold))| {
+                        self.block_candidates(block_idx, bitvecs, ins, outs)
+                            .into_iter()
+                            .map(|idx| (idx, point.distance(self.dataset.get(idx).unwrap())))
+                            .filter(|(_, d)| d <= threshold)
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut res = vec![vec![]; queries.len()];
+        for block in found {
+            for (res, found) in res.iter_mut().zip(block) {
+                res.extend(found);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Sort exclusion zones into those `point` must be in and those it must be out of.
+    fn classify(&self, point: &T, threshold: f64) -> (Vec<usize>, Vec<usize>) {
+        self.exclusions
+            .par_iter()
+            .enumerate()
+            .filter_map(|(idx, ez)| {
+                if ez.must_be_in(point, threshold) {
+                    Some(Either::Left(idx))
+                } else if ez.must_be_out(point, threshold) {
+                    Some(Either::Right(idx))
+                } else {
+                    None
+                }
+            })
+            .partition_map(|x| x)
+    }
+
+    /// Dataset indices of candidate points in a block, given the zones a query must be in and out of.
+    fn block_candidates(
+        &self,
+        block_idx: usize,
+        bitvecs: &[Column],
+        ins: &[usize],
+        outs: &[usize],
+    ) -> Vec<usize> {
+        let from = block_idx * self.block_size;
+        let len = self.block_size.min(self.dataset.len() - from);
+
+        // If no zones are decided (including when there are no zones at all), every point in the block is a candidate.
+        if ins.is_empty() && outs.is_empty() {
+            return (from..from + len).collect();
+        }
+
+        // Run-length coded zones are combined as runs, and only decoded once they have all been applied.
+        let mut mask = None::<Runs>;
+        let mut ands = None::<BitVec>;
+        let mut ors = None::<BitVec>;
+        for column in ins.iter().map(|&idx| &bitvecs[idx]) {
+            match (column, &mut ands) {
+                (Column::Dense(bv), Some(acc)) => acc.and_inplace(bv),
+                (Column::Dense(bv), None) => ands = Some(bv.clone()),
+                (Column::Runs(runs), _) => {
+                    let mask = mask.get_or_insert_with(|| Runs::full(len));
+                    *mask = mask.and(runs);
+                }
+            }
+        }
+        for column in outs.iter().map(|&idx| &bitvecs[idx]) {
+            match (column, &mut ors) {
+                (Column::Dense(bv), Some(acc)) => acc.or_inplace(bv),
+                (Column::Dense(bv), None) => ors = Some(bv.clone()),
+                (Column::Runs(runs), _) => {
+                    let mask = mask.get_or_insert_with(|| Runs::full(len));
+                    *mask = mask.and_not(runs);
+                }
+            }
+        }
+
+        let dense = match (ands, ors) {
+            (Some(ands), Some(ors)) => Some(ands.difference(ors)),
+            (Some(ands), None) => Some(ands),
+            (None, Some(ors)) => Some(!ors),
+            (None, None) => None,
+        };
+        let res = match (dense, mask) {
+            (Some(dense), Some(mask)) => dense & mask.dense(),
+            (Some(dense), None) => dense,
+            (None, Some(mask)) => {
+                return mask
+                    .ones()
+                    .flat_map(|(start, end)| from + start..from + end)
+                    .collect();
+            }
+            (None, None) => unreachable!("queries with no decided zones return early"),
+        };
+
+        res.into_usizes()
+            .into_iter()
+            .map(|internal_idx| from + internal_idx)
+            .collect::<Vec<_>>()
+    }
+
+    /// Recreate the built-in exclusion zones from their definitions. Custom zones are skipped.
+    fn builtin_exclusions(
         ref_points: &[T],
+        zones: &[ZoneDef],
     ) -> Vec<Box<dyn ExclusionSync<T> + 'a>> {
-        ref_points
+        zones
             .iter()
-            .combinations(2)
-            .map(|x| {
-                Box::new(SheetExclusion::new(x[0].clone(), x[1].clone(), 0.0))
-                    as Box<dyn ExclusionSync<T>>
-            })
+            .filter_map(|zone| zone.exclusion(ref_points))
+            .map(|ez| Box::new(ez) as Box<dyn ExclusionSync<T>>)
             .collect()
     }
 
@@ -166,83 +456,227 @@ where
         _block_size: usize,
         builder: &Builder<T>,
         exclusions: &[Box<dyn ExclusionSync<T> + 'a>],
-    ) -> Vec<Vec<BitVec>> {
+    ) -> Vec<Vec<Column>> {
         builder
             .dataset
             .par_chunks(_block_size)
             .map(|points| {
                 exclusions
                     .par_iter()
-                    .map(|ez| BitVec::from_bool_iterator(points.iter().map(|pt| ez.is_in(pt))))
+                    .map(|ez| Column::new(points.iter().map(|pt| ez.is_in(pt)).collect()))
                     .collect::<Vec<_>>()
             })
             .collect()
     }
+}
 
-    fn ratio(&self, ones: usize) -> f64 {
-        ones as f64 / self.dataset.len() as f64
+/// A builder which has been lent a dataset. The dataset is returned when the builder is dropped, even if building panics.
+struct Lent<'s, T> {
+    home: &'s mut Vec<T>,
+    builder: Builder<T>,
+}
+
+impl<T> Drop for Lent<'_, T> {
+    fn drop(&mut self) {
+        *self.home = std::mem::take(&mut self.builder.dataset);
     }
+}
 
-    /// Cull exclusion zones with low exclusion power.
-    /// This function will compare all zones in in the data structure with one another and calculate their [Hamming distance](https://en.wikipedia.org/wiki/Hamming_distance).
-    /// If a zone's similarity ratio is above the given `threshold`, it is marked for removal.
-    pub fn cull_by_similarity(&mut self, threshold: f64) {
-        let mut to_cull = HashSet::new();
-        for indices in (0..self.exclusions.len()).combinations(2) {
-            let i = indices[0];
-            let j = indices[1];
-            let hamming = {
-                self.bitset
-                    .iter()
-                    .map(|bvs| (bvs[i].xor_cloned(&bvs[j])).count_ones())
-                    .sum::<usize>()
-            };
+#[cfg(feature = "serde")]
+impl<'a, T> Parallel<'a, T>
+where
+    T: Metric + Send + Sync + Serialize + DeserializeOwned,
+    dyn ExclusionSync<T>: 'a,
+{
+    /// Save the data structure to a file at `path`, overwriting it if it already exists.
+    ///
+    /// The reference points, exclusion zones, builder parameters, block size and partitioning data are saved, but the dataset is not.
+    /// The same dataset must be given to [`load`](Parallel::load) to use the data structure again.
+    ///
+    /// # Errors
+    /// Returns [`PersistError::CustomZones`] if the data structure contains custom exclusion zones, as these cannot be saved.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PersistError> {
+        if self.zones.contains(&ZoneDef::Custom) {
+            return Err(PersistError::CustomZones);
+        }
+
+        persist::save(
+            path,
+            Kind::Parallel,
+            &(
+                self.dataset.len() as u64,
+                &self.params,
+                self.block_size as u64,
+                &self.ref_points,
+                &self.zones,
+                &self.bitset,
+            ),
+        )
+    }
+
+    /// Load a data structure saved with [`save`](Parallel::save) from a file at `path`.
+    ///
+    /// `dataset` must be the dataset the data structure was built from.
+    ///
+    /// # Errors
+    /// Returns [`PersistError::DatasetLength`] if `dataset` does not have the same number of points as the dataset the data
+    /// structure was built from, and [`PersistError::Corrupt`] if the contents of the file are inconsistent, including
+    /// run-length coded partitioning data which cannot be decoded.
+    pub fn load<I>(path: impl AsRef<Path>, dataset: I) -> Result<Self, PersistError>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        #[allow(clippy::type_complexity)]
+        let (len, params, block_size, ref_points, zones, bitset): (
+            u64,
+            Params,
+            u64,
+            Vec<T>,
+            Vec<ZoneDef>,
+            Vec<Vec<Column>>,
+        ) = persist::load(path, Kind::Parallel)?;
+
+        let dataset = dataset.into_iter().collect::<Vec<_>>();
+        if dataset.len() as u64 != len {
+            return Err(PersistError::DatasetLength {
+                expected: len as usize,
+                found: dataset.len(),
+            });
+        }
 
-            if 1.0 - self.ratio(hamming) > threshold {
-                to_cull.insert(j);
+        let block_size = block_size as usize;
+        if block_size == 0
+            || bitset.len() != dataset.len().div_ceil(block_size)
+            || !zones.iter().all(|zone| zone.is_valid(ref_points.len()))
+        {
+            return Err(PersistError::Corrupt);
+        }
+        for (block_idx, block) in bitset.iter().enumerate() {
+            let block_len = block_size.min(dataset.len() - block_idx * block_size);
+            if block.len() != zones.len()
+                || !block
+                    .iter()
+                    .all(|bv| bv.len() == block_len && bv.is_valid())
+            {
+                return Err(PersistError::Corrupt);
             }
         }
 
-        self.cull(to_cull)
+        Ok(Self {
+            exclusions: Self::builtin_exclusions(&ref_points, &zones),
+            dataset,
+            bitset,
+            block_size,
+            ref_points,
+            zones,
+            params,
+        })
+    }
+}
+
+impl<T> Cullable<T> for Parallel<'_, T>
+where
+    T: Metric + Send + Sync,
+{
+    type Error = CullError;
+
+    fn cull_by_similarity(&mut self, threshold: f64) -> Result<(), CullError> {
+        let keep = cull::keep_by_similarity(
+            self.exclusions.len(),
+            self.dataset.len(),
+            threshold,
+            self.hamming(),
+        );
+
+        self.cull(&keep);
+        Ok(())
+    }
+
+    fn cull_by_popcnt(&mut self, threshold: f64) -> Result<(), CullError> {
+        self.cull(&cull::keep_by_popcnt(
+            &self.popcnt(),
+            self.dataset.len(),
+            threshold,
+        ));
+        Ok(())
+    }
+
+    fn select_zones_for_workload(
+        &mut self,
+        sample_queries: &[T],
+        threshold: f64,
+        budget: usize,
+    ) -> Result<(), CullError> {
+        let decisions = sample_queries
+            .iter()
+            .map(|query| self.classify(query, threshold))
+            .collect::<Vec<_>>();
+
+        // Join each zone's blocks back into a single column.
+        let columns = (0..self.exclusions.len())
+            .into_par_iter()
+            .map(|zone| {
+                BitVec::from_bool_iterator(
+                    self.bitset
+                        .iter()
+                        .flat_map(|bvs| bvs[zone].dense().into_owned().into_bools()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        self.cull(&cull::keep_for_workload(
+            &columns,
+            &decisions,
+            self.dataset.len(),
+            budget,
+        )?);
+        Ok(())
     }
 
-    /// Cull exclusion zones with low exclusion power.
-    /// This function measures the exclusion power of a zone by counting the ratio of points that are in/out to the dataset.
-    /// If either ratio is above the `threshold` given, it is marked for removal.
-    pub fn cull_by_popcnt(&mut self, threshold: f64) {
+    fn cull_to(&mut self, n_zones: usize) -> Result<(), CullError> {
+        let keep = cull::keep_n(&self.popcnt(), self.dataset.len(), n_zones, self.hamming())?;
+
+        self.cull(&keep);
+        Ok(())
+    }
+}
+
+impl<T> Parallel<'_, T> {
+    /// Number of points in each zone.
+    fn popcnt(&self) -> Vec<usize> {
         let len = self.exclusions.len();
-        let mut to_cull = HashSet::new();
 
         // Count ones for each column, across all the blocks.
-        let popcnt = self.bitset.iter().fold(vec![0_usize; len], |acc, x| {
+        self.bitset.iter().fold(vec![0_usize; len], |acc, x| {
             acc.into_iter()
                 .zip(x.iter())
                 .map(|(a, b)| a + b.count_ones())
                 .collect()
-        });
-
-        for (idx, cnt) in popcnt.into_iter().enumerate() {
-            if self.ratio(cnt) > threshold || self.ratio(self.dataset.len() - cnt) > threshold {
-                to_cull.insert(idx);
-            }
-        }
-
-        self.cull(to_cull)
+        })
     }
 
-    fn cull(&mut self, to_cull: HashSet<usize>) {
-        let keep = (0..self.exclusions.len())
-            .map(|idx| !to_cull.contains(&idx))
+    /// Number of points for which two zones differ. Run-length coded blocks are decoded once, when this is called.
+    fn hamming(&self) -> impl Fn(usize, usize) -> usize + '_ {
+        let blocks = self
+            .bitset
+            .iter()
+            .map(|bvs| bvs.iter().map(Column::dense).collect::<Vec<_>>())
             .collect::<Vec<_>>();
 
-        for bvs in self.bitset.iter_mut() {
-            let mut iter = keep.iter();
-
-            bvs.retain(|_| *iter.next().unwrap());
+        move |i, j| {
+            blocks
+                .iter()
+                .map(|bvs| bvs[i].xor_cloned(&bvs[j]).count_ones())
+                .sum()
         }
+    }
 
-        let mut iter = keep.iter();
-        self.exclusions.retain(|_| *iter.next().unwrap());
+    fn cull(&mut self, keep: &[bool]) {
+        for bvs in self.bitset.iter_mut() {
+            cull::retain(bvs, keep);
+        }
+        cull::retain(&mut self.exclusions, keep);
+        cull::retain(&mut self.zones, keep);
     }
 }
 
@@ -253,11 +687,14 @@ mod tests {
     use std::fs;
 
     use super::*;
+    use crate::exclusions::Exclusion;
+    use crate::RefPointSelection;
+    use sisap_data::nasa::Nasa;
 
     pub(crate) const NASA: &str = include_str!("../sisap-data/src/nasa.ascii");
     pub(crate) const COLORS: &str = include_str!("../sisap-data/src/colors.ascii");
 
-    fn test<T>(dataset: &Vec<T>, bitpart: &Parallel<T>, query: T, threshold: f64)
+    fn test<T>(dataset: &[T], bitpart: &Parallel<T>, query: T, threshold: f64)
     where
         for<'a> T: Metric + Send + Sync + 'a,
     {
@@ -270,7 +707,7 @@ mod tests {
 
         // Check results match up with linear search
         let brute_force = dataset
-            .into_iter()
+            .iter()
             .map(|pt| pt.distance(&query))
             .filter(|d| *d <= threshold)
             .count();
@@ -293,6 +730,217 @@ mod tests {
         test(&nasa, &bitpart, query.clone(), threshold);
     }
 
+    #[test]
+    fn sisap_nasa_par_compressed() {
+        let mut nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+        // Sorting the dataset along one axis makes sheets along that axis split blocks into a handful of runs.
+        nasa.sort_by(|a, b| a[0].total_cmp(&b[0]));
+
+        let bitpart = Builder::new(nasa.clone(), 40).build_parallel(Some(512));
+        let columns = bitpart.bitset.iter().flatten();
+        assert!(columns.clone().any(|c| matches!(c, Column::Runs(_))));
+        assert!(columns.clone().any(|c| matches!(c, Column::Dense(_))));
+
+        for query in [317, 1000, 20000] {
+            test(&nasa, &bitpart, nasa[query].clone(), 1.0);
+        }
+    }
+
+    #[test]
+    fn sisap_nasa_par_insert() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        // The last block is partly full before points are inserted.
+        let mut bitpart = Builder::new(nasa[..20000].to_vec(), 40).build_parallel(Some(512));
+        assert_eq!(
+            bitpart.insert_batch(nasa[20000..nasa.len() - 1].to_vec()),
+            20000..nasa.len() - 1
+        );
+        assert_eq!(bitpart.insert(nasa[nasa.len() - 1].clone()), nasa.len() - 1);
+        assert_eq!(bitpart.len(), nasa.len());
+        assert_eq!(bitpart.bitset.len(), nasa.len().div_ceil(512));
+        for (blk_idx, block) in bitpart.bitset.iter().enumerate() {
+            let len = 512.min(nasa.len() - blk_idx * 512);
+            assert!(block.iter().all(|column| column.dense().len() == len));
+        }
+
+        for query in [317, 20100, 31700] {
+            test(&nasa, &bitpart, nasa[query].clone(), 1.0);
+        }
+    }
+
+    #[test]
+    fn sisap_nasa_par_indices() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40).build_parallel(Some(512));
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        let indices = bitpart
+            .range_search_indices(query.clone(), threshold)
+            .unwrap();
+        let refs = bitpart.range_search_ref(query.clone(), threshold).unwrap();
+
+        assert!(indices.contains(&(317, 0.0)));
+        assert_eq!(indices.len(), refs.len());
+        for ((idx, d1), (pt, d2)) in indices.into_iter().zip(refs) {
+            assert_eq!(d1, d2);
+            assert_eq!(nasa[idx].distance(&query), d1);
+            assert!(std::ptr::eq(pt, bitpart.dataset.get(idx).unwrap()));
+        }
+    }
+
+    #[test]
+    fn sisap_nasa_par_stats() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40).build_parallel(Some(512));
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        let (res, stats) = bitpart
+            .range_search_with_stats(query.clone(), threshold)
+            .unwrap();
+
+        assert_eq!(
+            stats.must_be_in + stats.must_be_out + stats.undecided,
+            bitpart.zones()
+        );
+        assert_eq!(stats.distance_calls, stats.candidates);
+        assert!(stats.candidates >= res.len());
+        assert_eq!(
+            res.len(),
+            bitpart.range_search(query, threshold).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn sisap_nasa_par_ref_point_selection() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        for selection in [
+            RefPointSelection::Random { seed: 42 },
+            RefPointSelection::FarthestFirst { seed: 42 },
+            RefPointSelection::MaxVariance {
+                seed: 42,
+                sample_size: 500,
+            },
+        ] {
+            let bitpart = Builder::new(nasa.clone(), 40)
+                .ref_point_selection(selection)
+                .build_parallel(Some(512));
+
+            test(&nasa, &bitpart, query.clone(), threshold);
+        }
+    }
+
+    #[test]
+    fn sisap_nasa_par_per_point_radii() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .per_point_radii(7)
+            .build_parallel(Some(512));
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        assert_eq!(bitpart.zones(), 40 * 7 + 780);
+        test(&nasa, &bitpart, query, threshold);
+    }
+
+    /// Splits points along one axis.
+    struct AxisExclusion {
+        axis: usize,
+        value: f64,
+    }
+
+    impl Exclusion<Euclidean<Nasa>> for AxisExclusion {
+        fn is_in(&self, point: &Euclidean<Nasa>) -> bool {
+            point[self.axis] < self.value
+        }
+
+        fn must_be_in(&self, point: &Euclidean<Nasa>, threshold: f64) -> bool {
+            point[self.axis] < self.value - threshold
+        }
+
+        fn must_be_out(&self, point: &Euclidean<Nasa>, threshold: f64) -> bool {
+            point[self.axis] >= self.value + threshold
+        }
+    }
+
+    impl ExclusionSync<Euclidean<Nasa>> for AxisExclusion {}
+
+    fn axis_exclusions() -> Vec<Box<dyn ExclusionSync<Euclidean<Nasa>>>> {
+        (0..20)
+            .map(|axis| Box::new(AxisExclusion { axis, value: 0.0 }) as Box<dyn ExclusionSync<_>>)
+            .collect()
+    }
+
+    #[test]
+    fn sisap_nasa_par_custom_exclusions() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .with_exclusions_sync(axis_exclusions())
+            .build_parallel(Some(512));
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        assert_eq!(bitpart.zones(), 200 + 780 + 20);
+        test(&nasa, &bitpart, query, threshold);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unsync_exclusions() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let exclusions = axis_exclusions()
+            .into_iter()
+            .map(|ez| ez as Box<dyn Exclusion<_>>)
+            .collect();
+
+        Builder::new(nasa, 40)
+            .with_exclusions(exclusions)
+            .build_parallel(Some(512));
+    }
+
     #[test]
     fn sisap_nasa_par_cull_popcnt() {
         let nasa = parse_nasa(NASA)
@@ -305,7 +953,48 @@ mod tests {
         let query = nasa[317].clone();
         let threshold = 1.0;
 
-        bitpart.cull_by_popcnt(0.95);
+        bitpart.cull_by_popcnt(0.95).unwrap();
+        test(&nasa, &bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_par_workload() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build_parallel(Some(512));
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        bitpart
+            .select_zones_for_workload(&nasa[1000..1010], threshold, 50)
+            .unwrap();
+        assert_eq!(bitpart.zones(), 50);
+        test(&nasa, &bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_par_cull_to() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build_parallel(Some(512));
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        bitpart.cull_to(100).unwrap();
+        assert_eq!(bitpart.zones(), 100);
+        test(&nasa, &bitpart, query.clone(), threshold);
+
+        // Unlike culling by ratio, at least one zone is always left.
+        bitpart.cull_to(1).unwrap();
+        assert_eq!(bitpart.zones(), 1);
         test(&nasa, &bitpart, query, threshold);
     }
 
@@ -321,7 +1010,7 @@ mod tests {
         let query = nasa[317].clone();
         let threshold = 1.0;
 
-        bitpart.cull_by_similarity(0.95);
+        bitpart.cull_by_similarity(0.95).unwrap();
         test(&nasa, &bitpart, query, threshold);
     }
 
@@ -340,6 +1029,23 @@ mod tests {
         test(&colors, &bitpart, query.clone(), threshold);
     }
 
+    #[test]
+    fn sisap_colors_par_four_point() {
+        let colors = parse_colors(COLORS)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(colors.clone(), 40)
+            .four_point(true)
+            .build_parallel(Some(512));
+        let query = colors[70446].clone();
+        let threshold = 0.5;
+
+        test(&colors, &bitpart, query, threshold);
+    }
+
     #[test]
     fn sisap_colors_par_cull_popcnt() {
         let colors = parse_colors(COLORS)
@@ -352,7 +1058,7 @@ mod tests {
         let query = colors[70446].clone();
         let threshold = 0.5;
 
-        bitpart.cull_by_popcnt(0.95);
+        bitpart.cull_by_popcnt(0.95).unwrap();
         test(&colors, &bitpart, query, threshold);
     }
 
@@ -368,12 +1074,11 @@ mod tests {
         let query = colors[70446].clone();
         let threshold = 0.5;
 
-        bitpart.cull_by_similarity(0.95);
+        bitpart.cull_by_similarity(0.95).unwrap();
         test(&colors, &bitpart, query, threshold);
     }
 
     #[test]
-    #[should_panic]
     fn no_zones() {
         let colors = parse_colors(COLORS)
             .unwrap()
@@ -385,8 +1090,197 @@ mod tests {
         let query = colors[70446].clone();
         let threshold = 0.5;
 
-        bitpart.cull_by_popcnt(0.0);
-        test(&colors, &bitpart, query, threshold);
+        bitpart.cull_by_popcnt(0.0).unwrap();
+        assert_eq!(bitpart.zones(), 0);
+        test(&colors, &bitpart, query.clone(), threshold);
+
+        let stats = bitpart
+            .range_search_with_stats(query.clone(), threshold)
+            .unwrap()
+            .1;
+        assert_eq!(stats.candidates, colors.len());
+
+        let batch = bitpart
+            .range_search_batch(std::slice::from_ref(&query), threshold)
+            .unwrap();
+        assert_eq!(
+            batch[0].len(),
+            bitpart.range_search(query, threshold).unwrap().len()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn sisap_nasa_save_load() {
+        let path = "/tmp/par_sisap_nasa_save_load.bitpart";
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(|pt| Euclidean::new(pt.0))
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build_parallel(Some(512));
+        bitpart.cull_to(100).unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+        bitpart.save(path).unwrap();
+
+        let loaded = Parallel::load(path, nasa.clone()).unwrap();
+        assert_eq!(loaded.zones(), 100);
+        assert_eq!(
+            loaded
+                .range_search_indices(query.clone(), threshold)
+                .unwrap(),
+            bitpart
+                .range_search_indices(query.clone(), threshold)
+                .unwrap()
+        );
+
+        assert!(matches!(
+            Parallel::load(path, nasa[1..].to_vec()),
+            Err(PersistError::DatasetLength { .. })
+        ));
+        assert!(matches!(
+            crate::Sequential::load(path, nasa.clone()),
+            Err(PersistError::WrongVariant)
+        ));
+
+        std::fs::remove_file(path).unwrap();
+        test(&nasa, &loaded, query, threshold);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn load_corrupt_runs() {
+        let path = "/tmp/par_load_corrupt_runs.bitpart";
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(|pt| Euclidean::new(pt.0))
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 10).build_parallel(Some(512));
+        bitpart.save(path).unwrap();
+
+        // Find the serialized toggles of the run-length coded column with the most runs, and move its first toggle past its end.
+        let runs = bitpart
+            .bitset
+            .iter()
+            .flatten()
+            .filter_map(|column| match column {
+                Column::Runs(runs) => Some(runs),
+                Column::Dense(_) => None,
+            })
+            .max_by_key(|runs| runs.ones().count())
+            .unwrap();
+        let toggles = runs
+            .ones()
+            .flat_map(|(start, end)| [start, end])
+            .filter(|&toggle| toggle < runs.len())
+            .collect::<Vec<_>>();
+        let needle = (toggles.len() as u64)
+            .to_le_bytes()
+            .into_iter()
+            .chain(toggles.iter().flat_map(|&t| (t as u32).to_le_bytes()))
+            .collect::<Vec<_>>();
+
+        let mut bytes = fs::read(path).unwrap();
+        let offsets = bytes
+            .windows(needle.len())
+            .enumerate()
+            .filter(|(_, window)| *window == needle)
+            .map(|(offset, _)| offset)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets.len(), 1);
+        bytes[offsets[0] + 8..offsets[0] + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(path, bytes).unwrap();
+
+        assert!(matches!(
+            Parallel::load(path, nasa),
+            Err(PersistError::Corrupt)
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn save_custom_exclusions() {
+        struct Never;
+
+        impl Exclusion<Euclidean<Vec<f64>>> for Never {
+            fn is_in(&self, _: &Euclidean<Vec<f64>>) -> bool {
+                false
+            }
+
+            fn must_be_in(&self, _: &Euclidean<Vec<f64>>, _: f64) -> bool {
+                false
+            }
+
+            fn must_be_out(&self, _: &Euclidean<Vec<f64>>, _: f64) -> bool {
+                false
+            }
+        }
+
+        impl ExclusionSync<Euclidean<Vec<f64>>> for Never {}
+
+        let points = (0..10)
+            .map(|i| Euclidean::new(vec![i as f64]))
+            .collect::<Vec<_>>();
+        let bitpart = Builder::new(points, 2)
+            .with_exclusions_sync(vec![Box::new(Never)])
+            .build_parallel(Some(4));
+
+        assert!(matches!(
+            bitpart.save("/tmp/par_save_custom_exclusions.bitpart"),
+            Err(PersistError::CustomZones)
+        ));
+    }
+
+    #[test]
+    fn rebuild() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build_parallel(Some(512));
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        bitpart.cull_by_popcnt(0.0).unwrap();
+        assert_eq!(bitpart.zones(), 0);
+
+        bitpart
+            .rebuild(20, |builder| builder.calibrate(1000, 0))
+            .unwrap();
+        assert_eq!(bitpart.zones(), 20 * 5 + 190);
+        assert_eq!(bitpart.len(), nasa.len());
+        test(&nasa, &bitpart, query.clone(), threshold);
+
+        // Failed rebuilds leave the data structure untouched.
+        assert!(matches!(
+            bitpart.rebuild(0, |builder| builder),
+            Err(BuildError::NoRefPoints)
+        ));
+        assert!(matches!(
+            bitpart.rebuild(40, |builder| builder.with_exclusions(
+                axis_exclusions()
+                    .into_iter()
+                    .map(|ez| ez as Box<dyn Exclusion<_>>)
+                    .collect()
+            )),
+            Err(BuildError::UnsyncExclusions)
+        ));
+
+        // Panics while configuring or building leave it untouched too.
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            bitpart.rebuild(20, |_| panic!("configure panicked"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(bitpart.len(), nasa.len());
+        assert_eq!(bitpart.zones(), 20 * 5 + 190);
+        test(&nasa, &bitpart, query, threshold);
     }
 
     #[test]
@@ -405,7 +1299,7 @@ mod tests {
         let queries = points
             .iter()
             .cloned()
-            .zip(nns.into_iter())
+            .zip(nns)
             .map(|(pt, nn)| (pt, nn.last().unwrap().1))
             .take(1000)
             .collect::<Vec<_>>();
@@ -417,6 +1311,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn nearest_neighbour_knn() {
+        let points = parse(&fs::read_to_string("data/100k_d20_flat.ascii").unwrap())
+            .unwrap()
+            .1
+             .1
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let nns: Vec<Vec<(usize, f64)>> =
+            serde_json::from_str(&fs::read_to_string("data/100k_d20_flat.json").unwrap()).unwrap();
+
+        let bitpart = Builder::new(points.clone(), 40).build_parallel(Some(8192));
+
+        for (query, nn) in points.iter().cloned().zip(nns).take(1000) {
+            let res = bitpart.knn_search(query, nn.len() + 1).unwrap();
+
+            // The closest point is always the query itself.
+            assert_eq!(res[0].1, 0.0);
+            assert_eq!(
+                res[1..].iter().map(|(_, d)| *d).collect::<Vec<_>>(),
+                nn.into_iter().map(|(_, d)| d).collect::<Vec<_>>()
+            );
+        }
+    }
+
     #[test]
     fn nearest_neighbour_cull_popcnt() {
         let points = parse(&fs::read_to_string("data/100k_d20_flat.ascii").unwrap())
@@ -433,14 +1354,14 @@ mod tests {
         let queries = points
             .iter()
             .cloned()
-            .zip(nns.into_iter())
+            .zip(nns)
             .map(|(pt, nn)| (pt, nn.last().unwrap().1))
             .take(1000)
             .collect::<Vec<_>>();
 
         let mut bitpart = Builder::new(points.clone(), 40).build_parallel(Some(8192));
 
-        bitpart.cull_by_popcnt(0.95);
+        bitpart.cull_by_popcnt(0.95).unwrap();
         for (query, threshold) in queries {
             test(&points, &bitpart, query, threshold);
         }
@@ -462,18 +1383,58 @@ mod tests {
         let queries = points
             .iter()
             .cloned()
-            .zip(nns.into_iter())
+            .zip(nns)
             .map(|(pt, nn)| (pt, nn.last().unwrap().1))
             .take(1000)
             .collect::<Vec<_>>();
 
         let mut bitpart = Builder::new(points.clone(), 40).build_parallel(Some(8192));
 
-        bitpart.cull_by_similarity(0.95);
+        bitpart.cull_by_similarity(0.95).unwrap();
         for (query, threshold) in queries {
             test(&points, &bitpart, query, threshold);
         }
     }
+
+    #[test]
+    fn nearest_neighbour_batch() {
+        let points = parse(&fs::read_to_string("data/100k_d20_flat.ascii").unwrap())
+            .unwrap()
+            .1
+             .1
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let nns: Vec<Vec<(usize, f64)>> =
+            serde_json::from_str(&fs::read_to_string("data/100k_d20_flat.json").unwrap()).unwrap();
+
+        let queries = points
+            .iter()
+            .cloned()
+            .zip(nns)
+            .map(|(pt, nn)| (pt, nn.last().unwrap().1))
+            .take(1000)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(points.clone(), 40).build_parallel(Some(8192));
+
+        let results = bitpart.range_search_batch_with_radii(&queries).unwrap();
+        assert_eq!(results.len(), queries.len());
+
+        for ((query, threshold), res) in queries.into_iter().zip(results) {
+            assert!(res
+                .iter()
+                .all(|(point, _)| point.distance(&query) <= threshold));
+
+            let brute_force = points
+                .iter()
+                .map(|pt| pt.distance(&query))
+                .filter(|d| *d <= threshold)
+                .count();
+            assert_eq!(res.len(), brute_force);
+        }
+    }
 }
 
 impl<T> Builder<T>
@@ -493,8 +1454,29 @@ where
     /// # Panics
     ///
     /// This function will panic if `block_size` is set to `Some(0)`. Use `None` if you want bitvector operations to be performed on a single thread.
+    ///
+    /// This function will also panic if exclusion zones were added with [`with_exclusions`](crate::Builder::with_exclusions), as they are not
+    /// guaranteed to be `Send` and `Sync`. Use [`with_exclusions_sync`](crate::Builder::with_exclusions_sync) instead.
+    ///
+    /// More generally, this function will panic if [`try_build_parallel`](crate::Builder::try_build_parallel) would return an error.
     pub fn build_parallel<'a>(self, block_size: Option<usize>) -> Parallel<'a, T> {
-        assert!(block_size != Some(0));
+        self.try_build_parallel(block_size)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Construct a [`Parallel`](crate::Parallel), returning an error instead of panicking.
+    ///
+    /// See [`build_parallel`](crate::Builder::build_parallel) for details.
+    pub fn try_build_parallel<'a>(
+        self,
+        block_size: Option<usize>,
+    ) -> Result<Parallel<'a, T>, BuildError> {
+        if block_size == Some(0) {
+            return Err(BuildError::ZeroBlockSize);
+        }
+        if self.has_unsync_exclusions() {
+            return Err(BuildError::UnsyncExclusions);
+        }
         Parallel::setup(self, block_size)
     }
 }
@@ -504,10 +1486,11 @@ where
 pub enum ParallelError {
     /// There are no exclusion zones in the data structure.
     ///
-    /// This error can only occur if [`cull_by_similarity`](crate::Parallel::cull_by_similarity) and/or
-    /// [`cull_by_similarity`](crate::Parallel::cull_by_popcnt) was used and no exclusion zones are left.
-    ///
-    /// **If this error is encountered, the data structure cannot be used again.**
+    /// This error is no longer returned. A data structure with no exclusion zones left falls back to a linear scan, and
+    /// zones can be restored with [`rebuild`](crate::Parallel::rebuild).
+    #[deprecated(
+        note = "queries on a data structure with no exclusion zones fall back to a linear scan"
+    )]
     #[error("no exclusion zones defined")]
     NoZones,
 }
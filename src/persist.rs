@@ -0,0 +1,155 @@
+//! Versioned binary format used to save and load data structures.
+//!
+//! Every file starts with a header: the magic bytes `BITPART\0`, the format version as a little-endian `u32`, and a single byte
+//! identifying which variant of BitPart saved it. For [`Sequential`](crate::Sequential) and [`Parallel`](crate::Parallel), the rest of
+//! the file is the [`bincode`] encoding of the data structure's contents. [`Disk`](crate::Disk) lays out its index file itself.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+use thiserror::Error;
+
+const MAGIC: &[u8; 8] = b"BITPART\0";
+const VERSION: u32 = 1;
+
+/// Variant of BitPart which saved a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Sequential = 0,
+    #[cfg(feature = "par")]
+    Parallel = 1,
+    #[cfg(feature = "disk")]
+    Disk = 2,
+}
+
+/// Write a header for `kind`, followed by `contents`, to a new file at `path`.
+pub(crate) fn save<S>(path: impl AsRef<Path>, kind: Kind, contents: &S) -> Result<(), PersistError>
+where
+    S: Serialize + ?Sized,
+{
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_header(&mut writer, kind)?;
+    bincode::serialize_into(&mut writer, contents)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read the contents of a file at `path`, checking that its header matches `kind`.
+pub(crate) fn load<S>(path: impl AsRef<Path>, kind: Kind) -> Result<S, PersistError>
+where
+    S: DeserializeOwned,
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    read_header(&mut reader, kind)?;
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+/// Length of the header in bytes.
+#[cfg(feature = "disk")]
+pub(crate) const HEADER_LEN: usize = MAGIC.len() + 4 + 1;
+
+/// Write a header for `kind`.
+pub(crate) fn write_header(writer: &mut impl Write, kind: Kind) -> Result<(), PersistError> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&[kind as u8])?;
+    Ok(())
+}
+
+/// Read a header, checking that it matches `kind`.
+pub(crate) fn read_header(reader: &mut impl Read, kind: Kind) -> Result<(), PersistError> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(PersistError::NotBitPart);
+    }
+
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        return Err(PersistError::UnsupportedVersion(version));
+    }
+
+    let mut found = [0; 1];
+    reader.read_exact(&mut found)?;
+    if found[0] != kind as u8 {
+        return Err(PersistError::WrongVariant);
+    }
+    Ok(())
+}
+
+/// CRC-32 (IEEE) checksum of `bytes`.
+#[cfg(feature = "disk")]
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = match crc & 1 {
+                    1 => 0xEDB8_8320 ^ (crc >> 1),
+                    _ => crc >> 1,
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !bytes.iter().fold(!0, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Errors that can be encountered while saving or loading a data structure.
+#[derive(Debug, Error)]
+pub enum PersistError {
+    /// Generic IO error. This means the file could not be created, opened, read or written.
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    /// Could not (de)serialize the data structure.
+    #[error("could not (de)serialize data structure")]
+    Serde(#[from] bincode::Error),
+    /// The file was not written by BitPart.
+    #[error("not a bitpart file")]
+    NotBitPart,
+    /// The file was written by an incompatible version of BitPart.
+    #[error("unsupported file version {0}")]
+    UnsupportedVersion(u32),
+    /// The file was saved by a different variant of BitPart.
+    #[error("file was saved by a different variant of bitpart")]
+    WrongVariant,
+    /// The dataset given when loading does not have the same number of points as the one the data structure was built from.
+    #[error("dataset has {found} points, but the data structure was built from {expected}")]
+    DatasetLength {
+        /// Number of points in the dataset the data structure was built from.
+        expected: usize,
+        /// Number of points in the dataset given.
+        found: usize,
+    },
+    /// The contents of the file are inconsistent with each other.
+    #[error("file is corrupt")]
+    Corrupt,
+    /// The data structure contains custom exclusion zones, which cannot be saved.
+    #[error("custom exclusion zones cannot be saved")]
+    CustomZones,
+}
+
+#[cfg(all(test, feature = "disk"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
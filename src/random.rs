@@ -0,0 +1,68 @@
+//! Seeded random sampling, used to choose reference points and calibration samples.
+
+use std::collections::HashMap;
+
+/// SplitMix64 pseudorandom number generator.
+///
+/// Sampling only needs to be reproducible from a seed and spread evenly over the dataset, so a small generator is used rather than
+/// a dependency on a general-purpose random number library.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, which must not be empty.
+    ///
+    /// The bias of the multiply-shift reduction is at most `n / 2^64`, which is negligible for dataset indices.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    /// `amount` distinct indices in `0..len`, in random order.
+    ///
+    /// This is a partial Fisher-Yates shuffle which only records the positions it has swapped, so it takes `O(amount)` time and memory.
+    pub(crate) fn sample(&mut self, len: usize, amount: usize) -> Vec<usize> {
+        let mut swapped = HashMap::new();
+        (0..amount.min(len))
+            .map(|i| {
+                let j = i + self.below(len - i);
+                let picked = swapped.get(&j).copied().unwrap_or(j);
+                swapped.insert(j, swapped.get(&i).copied().unwrap_or(i));
+                picked
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample() {
+        let sample = Rng::new(7).sample(1000, 100);
+        assert_eq!(sample, Rng::new(7).sample(1000, 100));
+        assert_ne!(sample, Rng::new(8).sample(1000, 100));
+
+        let mut sorted = sample.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 100);
+        assert!(sorted.iter().all(|&idx| idx < 1000));
+
+        // Sampling everything is a permutation.
+        let mut all = Rng::new(0).sample(10, 20);
+        all.sort();
+        assert_eq!(all, (0..10).collect::<Vec<_>>());
+    }
+}
@@ -1,12 +1,21 @@
 use std::convert::Infallible;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
 
-use crate::builder::Builder;
-use crate::exclusions::{BallExclusion, Exclusion, SheetExclusion};
+use crate::builder::{BuildError, Builder, Params};
+use crate::cull::{self, CullError, Cullable};
+use crate::exclusions::{Exclusion, ZoneDef};
 use crate::metric::Metric;
-use crate::BitPart;
+#[cfg(feature = "serde")]
+use crate::persist::{self, Kind, PersistError};
+use crate::{BitPart, QueryStats};
 
 use bitvec_simd::BitVec;
-use itertools::Itertools;
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(feature = "serde")]
+use std::path::Path;
 
 /// Sequential BitPart.
 ///
@@ -17,6 +26,10 @@ pub struct Sequential<'a, T> {
     dataset: Vec<T>,
     exclusions: Vec<Box<dyn Exclusion<T> + 'a>>,
     bitset: Vec<BitVec>,
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    ref_points: Vec<T>,
+    zones: Vec<ZoneDef>,
+    params: Params,
 }
 
 impl<T> BitPart<T> for Sequential<'_, T>
@@ -26,35 +39,55 @@ where
     type Error = Infallible;
 
     fn range_search(&self, point: T, threshold: f64) -> Result<Vec<(T, f64)>, Self::Error> {
-        let mut ins = vec![];
-        let mut outs = vec![];
+        Ok(self
+            .search(&point, threshold)
+            .into_iter()
+            .map(|(idx, dist)| (self.dataset[idx].clone(), dist))
+            .collect())
+    }
 
-        for (idx, ez) in self.exclusions.iter().enumerate() {
-            if ez.must_be_in(&point, threshold) {
-                ins.push(idx);
-            } else if ez.must_be_out(&point, threshold) {
-                outs.push(idx);
-            }
-        }
+    fn range_search_indices(
+        &self,
+        point: T,
+        threshold: f64,
+    ) -> Result<Vec<(usize, f64)>, Self::Error> {
+        Ok(self.search(&point, threshold))
+    }
 
-        let ands: BitVec = ins
-            .iter()
-            .map(|&i| self.bitset.get(i).unwrap())
-            .fold(BitVec::ones(self.dataset.len()), |acc, v| acc & v);
+    fn range_search_ref(&self, point: T, threshold: f64) -> Result<Vec<(&T, f64)>, Self::Error> {
+        Ok(self
+            .search(&point, threshold)
+            .into_iter()
+            .map(|(idx, dist)| (&self.dataset[idx], dist))
+            .collect())
+    }
 
-        let nots: BitVec = !outs
-            .iter()
-            .map(|&i| self.bitset.get(i).unwrap())
-            .fold(BitVec::zeros(self.dataset.len()), |acc, v| acc | v);
+    fn range_search_with_stats(
+        &self,
+        point: T,
+        threshold: f64,
+    ) -> Result<(Vec<(T, f64)>, QueryStats), Self::Error> {
+        let (res, stats) = self.search_with_stats(&point, threshold);
 
-        let candidates = ands & nots;
+        Ok((
+            res.into_iter()
+                .map(|(idx, dist)| (self.dataset[idx].clone(), dist))
+                .collect(),
+            stats,
+        ))
+    }
 
-        Ok(candidates
-            .into_usizes()
+    fn knn_search(&self, point: T, k: usize) -> Result<Vec<(T, f64)>, Self::Error> {
+        let res = crate::expanding_knn(
+            self.dataset.len(),
+            k,
+            self.params.radius_increment,
+            |radius| Ok::<_, Self::Error>(self.search(&point, radius)),
+        )?;
+
+        Ok(res
             .into_iter()
-            .map(|i| self.dataset.get(i).unwrap())
-            .map(|pt| (pt.clone(), pt.distance(&point)))
-            .filter(|(_, dist)| *dist <= threshold)
+            .map(|(idx, dist)| (self.dataset[idx].clone(), dist))
             .collect())
     }
 
@@ -76,48 +109,124 @@ where
     T: Metric,
     dyn Exclusion<T>: 'a,
 {
-    pub(crate) fn setup(builder: Builder<T>) -> Self {
-        // TODO: actually randomise this
-        let ref_points = &builder.dataset[0..(builder.ref_points as usize)];
-        let mut exclusions = Self::ball_exclusions(&builder, ref_points);
-        exclusions.extend(Self::sheet_exclusions(&builder, ref_points));
+    pub(crate) fn setup(builder: Builder<T>) -> Result<Self, BuildError> {
+        builder.validate()?;
+        let ref_points = builder.select_ref_points();
+        let zones = builder.zone_defs(&ref_points)?;
+        let params = builder.params();
+        let mut exclusions = Self::builtin_exclusions(&ref_points, &zones);
+        exclusions.extend(
+            builder
+                .exclusions
+                .iter()
+                .map(|ez| Box::new(Arc::clone(ez)) as Box<dyn Exclusion<T>>),
+        );
         let bitset = Self::make_bitset(&builder, &exclusions);
-        Self {
+        Ok(Self {
             dataset: builder.dataset,
             bitset,
             exclusions,
+            ref_points,
+            zones,
+            params,
+        })
+    }
+
+    /// Insert `point` into the dataset, returning its index.
+    ///
+    /// See [`insert_batch`](Sequential::insert_batch) for details.
+    pub fn insert(&mut self, point: T) -> usize {
+        self.insert_batch([point]).start
+    }
+
+    /// Insert `points` into the dataset, returning the range of their indices.
+    ///
+    /// Each point is checked against the existing exclusion zones, which are not redefined. If the dataset grows to look very
+    /// different from the points the data structure was built from, rebuilding it gives more effective zones.
+    pub fn insert_batch(&mut self, points: impl IntoIterator<Item = T>) -> Range<usize> {
+        let start = self.dataset.len();
+        self.dataset.extend(points);
+
+        for (bv, ez) in self.bitset.iter_mut().zip(&self.exclusions) {
+            bv.resize(self.dataset.len(), false);
+            for (idx, pt) in self.dataset.iter().enumerate().skip(start) {
+                if ez.is_in(pt) {
+                    bv.set(idx, true);
+                }
+            }
         }
+
+        start..self.dataset.len()
     }
 
-    fn ball_exclusions(builder: &Builder<T>, ref_points: &[T]) -> Vec<Box<dyn Exclusion<T> + 'a>> {
-        let radii = [
-            builder.mean_distance - 2.0 * builder.radius_increment,
-            builder.mean_distance - builder.radius_increment,
-            builder.mean_distance,
-            builder.mean_distance + builder.radius_increment,
-            builder.mean_distance + 2.0 * builder.radius_increment,
-        ];
+    /// Range search returning the indices of matching points, along with their distance from `point`.
+    fn search(&self, point: &T, threshold: f64) -> Vec<(usize, f64)> {
+        self.search_with_stats(point, threshold).0
+    }
 
-        ref_points
+    /// Range search returning the indices of matching points, along with statistics about the query.
+    fn search_with_stats(&self, point: &T, threshold: f64) -> (Vec<(usize, f64)>, QueryStats) {
+        let start = Instant::now();
+        let (ins, outs) = self.classify(point, threshold);
+        let exclusion_time = start.elapsed();
+
+        let start = Instant::now();
+        let ands: BitVec = ins
             .iter()
-            .cartesian_product(radii.into_iter())
-            .map(|(point, radius)| {
-                Box::new(BallExclusion::new(point.clone(), radius)) as Box<dyn Exclusion<T>>
-            })
-            .collect()
+            .map(|&i| self.bitset.get(i).unwrap())
+            .fold(BitVec::ones(self.dataset.len()), |acc, v| acc & v);
+
+        let nots: BitVec = !outs
+            .iter()
+            .map(|&i| self.bitset.get(i).unwrap())
+            .fold(BitVec::zeros(self.dataset.len()), |acc, v| acc | v);
+
+        let candidates = (ands & nots).into_usizes();
+        let filter_time = start.elapsed();
+
+        let start = Instant::now();
+        let res = candidates
+            .iter()
+            .map(|&i| (i, self.dataset.get(i).unwrap().distance(point)))
+            .filter(|(_, dist)| *dist <= threshold)
+            .collect();
+        let verify_time = start.elapsed();
+
+        let stats = QueryStats {
+            must_be_in: ins.len(),
+            must_be_out: outs.len(),
+            undecided: self.exclusions.len() - ins.len() - outs.len(),
+            candidates: candidates.len(),
+            distance_calls: candidates.len(),
+            exclusion_time,
+            filter_time,
+            verify_time,
+        };
+
+        (res, stats)
+    }
+
+    /// Indices of the exclusion zones which `point` must be in, and must be out of, respectively.
+    fn classify(&self, point: &T, threshold: f64) -> (Vec<usize>, Vec<usize>) {
+        let mut ins = vec![];
+        let mut outs = vec![];
+
+        for (idx, ez) in self.exclusions.iter().enumerate() {
+            if ez.must_be_in(point, threshold) {
+                ins.push(idx);
+            } else if ez.must_be_out(point, threshold) {
+                outs.push(idx);
+            }
+        }
+        (ins, outs)
     }
 
-    fn sheet_exclusions(
-        _builder: &Builder<T>,
-        ref_points: &[T],
-    ) -> Vec<Box<dyn Exclusion<T> + 'a>> {
-        ref_points
+    /// Recreate the built-in exclusion zones from their definitions. Custom zones are skipped.
+    fn builtin_exclusions(ref_points: &[T], zones: &[ZoneDef]) -> Vec<Box<dyn Exclusion<T> + 'a>> {
+        zones
             .iter()
-            .combinations(2)
-            .map(|x| {
-                Box::new(SheetExclusion::new(x[0].clone(), x[1].clone(), 0.0))
-                    as Box<dyn Exclusion<T>>
-            })
+            .filter_map(|zone| zone.exclusion(ref_points))
+            .map(|ez| Box::new(ez) as Box<dyn Exclusion<T>>)
             .collect()
     }
 
@@ -129,6 +238,155 @@ where
     }
 }
 
+#[cfg(feature = "serde")]
+impl<'a, T> Sequential<'a, T>
+where
+    T: Metric + Serialize + DeserializeOwned,
+    dyn Exclusion<T>: 'a,
+{
+    /// Save the data structure to a file at `path`, overwriting it if it already exists.
+    ///
+    /// The reference points, exclusion zones, builder parameters and partitioning data are saved, but the dataset is not.
+    /// The same dataset must be given to [`load`](Sequential::load) to use the data structure again.
+    ///
+    /// # Errors
+    /// Returns [`PersistError::CustomZones`] if the data structure contains custom exclusion zones, as these cannot be saved.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PersistError> {
+        if self.zones.contains(&ZoneDef::Custom) {
+            return Err(PersistError::CustomZones);
+        }
+
+        persist::save(
+            path,
+            Kind::Sequential,
+            &(
+                self.dataset.len() as u64,
+                &self.params,
+                &self.ref_points,
+                &self.zones,
+                &self.bitset,
+            ),
+        )
+    }
+
+    /// Load a data structure saved with [`save`](Sequential::save) from a file at `path`.
+    ///
+    /// `dataset` must be the dataset the data structure was built from.
+    ///
+    /// # Errors
+    /// Returns [`PersistError::DatasetLength`] if `dataset` does not have the same number of points as the dataset the data
+    /// structure was built from, and [`PersistError::Corrupt`] if the contents of the file are inconsistent.
+    pub fn load<I>(path: impl AsRef<Path>, dataset: I) -> Result<Self, PersistError>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let (len, params, ref_points, zones, bitset): (
+            u64,
+            Params,
+            Vec<T>,
+            Vec<ZoneDef>,
+            Vec<BitVec>,
+        ) = persist::load(path, Kind::Sequential)?;
+
+        let dataset = dataset.into_iter().collect::<Vec<_>>();
+        if dataset.len() as u64 != len {
+            return Err(PersistError::DatasetLength {
+                expected: len as usize,
+                found: dataset.len(),
+            });
+        }
+
+        if zones.len() != bitset.len()
+            || !zones.iter().all(|zone| zone.is_valid(ref_points.len()))
+            || !bitset.iter().all(|bv| bv.len() == dataset.len())
+        {
+            return Err(PersistError::Corrupt);
+        }
+
+        Ok(Self {
+            exclusions: Self::builtin_exclusions(&ref_points, &zones),
+            dataset,
+            bitset,
+            ref_points,
+            zones,
+            params,
+        })
+    }
+}
+
+impl<T> Cullable<T> for Sequential<'_, T>
+where
+    T: Metric,
+{
+    type Error = CullError;
+
+    fn cull_by_similarity(&mut self, threshold: f64) -> Result<(), CullError> {
+        let keep =
+            cull::keep_by_similarity(self.bitset.len(), self.dataset.len(), threshold, |i, j| {
+                self.hamming(i, j)
+            });
+
+        self.cull(&keep);
+        Ok(())
+    }
+
+    fn cull_by_popcnt(&mut self, threshold: f64) -> Result<(), CullError> {
+        self.cull(&cull::keep_by_popcnt(
+            &self.popcnt(),
+            self.dataset.len(),
+            threshold,
+        ));
+        Ok(())
+    }
+
+    fn select_zones_for_workload(
+        &mut self,
+        sample_queries: &[T],
+        threshold: f64,
+        budget: usize,
+    ) -> Result<(), CullError> {
+        let decisions = sample_queries
+            .iter()
+            .map(|query| self.classify(query, threshold))
+            .collect::<Vec<_>>();
+
+        self.cull(&cull::keep_for_workload(
+            &self.bitset,
+            &decisions,
+            self.dataset.len(),
+            budget,
+        )?);
+        Ok(())
+    }
+
+    fn cull_to(&mut self, n_zones: usize) -> Result<(), CullError> {
+        let keep = cull::keep_n(&self.popcnt(), self.dataset.len(), n_zones, |i, j| {
+            self.hamming(i, j)
+        })?;
+
+        self.cull(&keep);
+        Ok(())
+    }
+}
+
+impl<T> Sequential<'_, T> {
+    /// Number of points in each zone.
+    fn popcnt(&self) -> Vec<usize> {
+        self.bitset.iter().map(|bv| bv.count_ones()).collect()
+    }
+
+    /// Number of points for which zones `i` and `j` differ.
+    fn hamming(&self, i: usize, j: usize) -> usize {
+        self.bitset[i].xor_cloned(&self.bitset[j]).count_ones()
+    }
+
+    fn cull(&mut self, keep: &[bool]) {
+        cull::retain(&mut self.bitset, keep);
+        cull::retain(&mut self.exclusions, keep);
+        cull::retain(&mut self.zones, keep);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::metric::Euclidean;
@@ -152,7 +410,7 @@ mod tests {
 
         // Check results match up with linear search
         let brute_force = dataset
-            .into_iter()
+            .iter()
             .map(|pt| pt.distance(&query))
             .filter(|d| *d < threshold)
             .count();
@@ -160,6 +418,26 @@ mod tests {
         assert_eq!(res.len(), brute_force);
     }
 
+    fn test_knn<T>(dataset: Vec<T>, bitpart: Sequential<T>, query: T, k: usize)
+    where
+        for<'a> T: Metric + 'a,
+    {
+        let res = bitpart.knn_search(query.clone(), k).unwrap();
+
+        // Check results match up with a sorted linear search
+        let mut brute_force = dataset
+            .into_iter()
+            .map(|pt| pt.distance(&query))
+            .collect::<Vec<_>>();
+        brute_force.sort_by(|a, b| a.total_cmp(b));
+        brute_force.truncate(k);
+
+        assert_eq!(
+            res.into_iter().map(|(_, d)| d).collect::<Vec<_>>(),
+            brute_force
+        );
+    }
+
     #[test]
     fn sisap_nasa() {
         let nasa = parse_nasa(NASA)
@@ -175,6 +453,27 @@ mod tests {
         test(nasa, bitpart, query, threshold);
     }
 
+    #[test]
+    fn sisap_nasa_insert() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa[..20000].to_vec(), 40).build();
+        assert_eq!(
+            bitpart.insert_batch(nasa[20000..nasa.len() - 1].to_vec()),
+            20000..nasa.len() - 1
+        );
+        assert_eq!(bitpart.insert(nasa[nasa.len() - 1].clone()), nasa.len() - 1);
+        assert_eq!(bitpart.len(), nasa.len());
+        assert!(bitpart.bitset.iter().all(|bv| bv.len() == nasa.len()));
+
+        let query = nasa[31700].clone();
+        test(nasa, bitpart, query, 1.0);
+    }
+
     #[test]
     fn sisap_colors() {
         let colors = parse_colors(COLORS)
@@ -189,4 +488,338 @@ mod tests {
 
         test(colors, bitpart, query, threshold);
     }
+
+    #[test]
+    fn sisap_colors_calibrate() {
+        let colors = parse_colors(COLORS)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(colors.clone(), 40).calibrate(1000, 0).build();
+        let query = colors[70446].clone();
+        let threshold = 0.5;
+
+        test(colors, bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_balance_sheets() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .balance_sheets(1000, 0)
+            .build();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        test(nasa, bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_four_point() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .four_point(true)
+            .balance_sheets(1000, 0)
+            .build();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        test(nasa, bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_custom_exclusions() {
+        use crate::metric::Euclidean;
+        use sisap_data::nasa::Nasa;
+        use std::{cell::Cell, rc::Rc};
+
+        /// Splits points along one axis, and counts how many points it has been asked about.
+        struct AxisExclusion {
+            axis: usize,
+            value: f64,
+            calls: Rc<Cell<usize>>,
+        }
+
+        impl Exclusion<Euclidean<Nasa>> for AxisExclusion {
+            fn is_in(&self, point: &Euclidean<Nasa>) -> bool {
+                self.calls.set(self.calls.get() + 1);
+                point[self.axis] < self.value
+            }
+
+            fn must_be_in(&self, point: &Euclidean<Nasa>, threshold: f64) -> bool {
+                point[self.axis] < self.value - threshold
+            }
+
+            fn must_be_out(&self, point: &Euclidean<Nasa>, threshold: f64) -> bool {
+                point[self.axis] >= self.value + threshold
+            }
+        }
+
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let calls = Rc::new(Cell::new(0));
+        let exclusions = (0..20)
+            .map(|axis| {
+                Box::new(AxisExclusion {
+                    axis,
+                    value: 0.0,
+                    calls: Rc::clone(&calls),
+                }) as Box<dyn Exclusion<_>>
+            })
+            .collect();
+
+        let bitpart = Builder::new(nasa.clone(), 40)
+            .with_exclusions(exclusions)
+            .build();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        assert_eq!(bitpart.zones(), 200 + 780 + 20);
+        assert_eq!(calls.get(), 20 * nasa.len());
+        test(nasa, bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_cull_popcnt() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        bitpart.cull_by_popcnt(0.95).unwrap();
+        assert!(bitpart.zones() < 200 + 780);
+        test(nasa, bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_cull_similarity() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        bitpart.cull_by_similarity(0.95).unwrap();
+        assert!(bitpart.zones() < 200 + 780);
+        test(nasa, bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_cull_to() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        assert!(matches!(bitpart.cull_to(0), Err(CullError::NoZonesKept)));
+        assert_eq!(bitpart.zones(), 40 * 5 + 780);
+
+        bitpart.cull_to(100).unwrap();
+        assert_eq!(bitpart.zones(), 100);
+        test(nasa, bitpart, query, threshold);
+    }
+
+    #[test]
+    fn sisap_nasa_workload() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+        let sample = &nasa[1000..1010];
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build();
+        assert!(matches!(
+            bitpart.select_zones_for_workload(sample, threshold, 0),
+            Err(CullError::NoZonesKept)
+        ));
+        assert_eq!(bitpart.zones(), 40 * 5 + 780);
+
+        bitpart
+            .select_zones_for_workload(sample, threshold, 50)
+            .unwrap();
+        assert_eq!(bitpart.zones(), 50);
+
+        // The selected zones should prune the sample queries better than an arbitrary selection of the same size.
+        let mut first = Builder::new(nasa.clone(), 40).build();
+        first.cull(&(0..first.zones()).map(|idx| idx < 50).collect::<Vec<_>>());
+        let candidates = |bitpart: &Sequential<_>| {
+            sample
+                .iter()
+                .map(|q| bitpart.search_with_stats(q, threshold).1.candidates)
+                .sum::<usize>()
+        };
+        assert!(candidates(&bitpart) <= candidates(&first));
+
+        test(nasa, bitpart, query, threshold);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn sisap_nasa_save_load() {
+        let path = "/tmp/sisap_nasa_save_load.bitpart";
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(|pt| Euclidean::new(pt.0))
+            .collect::<Vec<_>>();
+
+        let mut bitpart = Builder::new(nasa.clone(), 40).build();
+        bitpart.cull_to(100).unwrap();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+        bitpart.save(path).unwrap();
+
+        let loaded = Sequential::load(path, nasa.clone()).unwrap();
+        assert_eq!(loaded.zones(), 100);
+        assert_eq!(
+            loaded
+                .range_search_indices(query.clone(), threshold)
+                .unwrap(),
+            bitpart
+                .range_search_indices(query.clone(), threshold)
+                .unwrap()
+        );
+
+        assert!(matches!(
+            Sequential::load(path, nasa[1..].to_vec()),
+            Err(PersistError::DatasetLength { expected, found }) if expected == nasa.len() && found == nasa.len() - 1
+        ));
+
+        std::fs::remove_file(path).unwrap();
+        test(nasa, loaded, query, threshold);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn save_custom_exclusions() {
+        struct Never;
+
+        impl Exclusion<Euclidean<Vec<f64>>> for Never {
+            fn is_in(&self, _: &Euclidean<Vec<f64>>) -> bool {
+                false
+            }
+
+            fn must_be_in(&self, _: &Euclidean<Vec<f64>>, _: f64) -> bool {
+                false
+            }
+
+            fn must_be_out(&self, _: &Euclidean<Vec<f64>>, _: f64) -> bool {
+                false
+            }
+        }
+
+        let points = (0..10)
+            .map(|i| Euclidean::new(vec![i as f64]))
+            .collect::<Vec<_>>();
+        let bitpart = Builder::new(points, 2)
+            .with_exclusions(vec![Box::new(Never)])
+            .build();
+
+        assert!(matches!(
+            bitpart.save("/tmp/save_custom_exclusions.bitpart"),
+            Err(PersistError::CustomZones)
+        ));
+    }
+
+    #[test]
+    fn sisap_nasa_knn() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40).build();
+        let query = nasa[317].clone();
+
+        test_knn(nasa, bitpart, query, 10);
+    }
+
+    #[test]
+    fn sisap_nasa_indices() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40).build();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        let indices = bitpart
+            .range_search_indices(query.clone(), threshold)
+            .unwrap();
+        let refs = bitpart.range_search_ref(query.clone(), threshold).unwrap();
+
+        assert!(indices.contains(&(317, 0.0)));
+        assert_eq!(indices.len(), refs.len());
+        for ((idx, d1), (pt, d2)) in indices.into_iter().zip(refs) {
+            assert_eq!(d1, d2);
+            assert_eq!(nasa[idx].distance(&query), d1);
+            assert!(std::ptr::eq(pt, bitpart.dataset.get(idx).unwrap()));
+        }
+    }
+
+    #[test]
+    fn sisap_nasa_stats() {
+        let nasa = parse_nasa(NASA)
+            .unwrap()
+            .into_iter()
+            .map(Euclidean::new)
+            .collect::<Vec<_>>();
+
+        let bitpart = Builder::new(nasa.clone(), 40).build();
+        let query = nasa[317].clone();
+        let threshold = 1.0;
+
+        let (res, stats) = bitpart
+            .range_search_with_stats(query.clone(), threshold)
+            .unwrap();
+
+        assert_eq!(
+            stats.must_be_in + stats.must_be_out + stats.undecided,
+            bitpart.zones()
+        );
+        assert_eq!(stats.distance_calls, stats.candidates);
+        assert!(stats.candidates >= res.len());
+        assert_eq!(
+            res.len(),
+            bitpart.range_search(query, threshold).unwrap().len()
+        );
+    }
 }
@@ -0,0 +1,258 @@
+use crate::metric::Euclidean;
+use crate::DiskError;
+
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Points which are vectors with a fixed number of dimensions.
+///
+/// Such points can be kept in a memory-mapped point store by [`Disk`](crate::Disk) instead of in memory. See
+/// [`build_on_disk_mapped`](crate::Builder::build_on_disk_mapped).
+pub trait FixedDimension: Sized {
+    /// The number of dimensions of every point of this type, or `None` if it is only fixed within a dataset.
+    ///
+    /// This is checked against the dimensions of the points in a point store when it is [opened](crate::Disk::open_mapped).
+    fn dimensions() -> Option<usize>;
+
+    /// The coordinates of the point.
+    fn row(&self) -> &[f64];
+
+    /// Recreate a point from its coordinates.
+    fn from_row(row: &[f64]) -> Self;
+}
+
+impl<const N: usize> FixedDimension for Euclidean<[f64; N]> {
+    fn dimensions() -> Option<usize> {
+        Some(N)
+    }
+
+    fn row(&self) -> &[f64] {
+        &**self
+    }
+
+    fn from_row(row: &[f64]) -> Self {
+        Euclidean::new(
+            row.try_into()
+                .unwrap_or_else(|_| panic!("row has the wrong number of dimensions")),
+        )
+    }
+}
+
+impl FixedDimension for Euclidean<Vec<f64>> {
+    fn dimensions() -> Option<usize> {
+        None
+    }
+
+    fn row(&self) -> &[f64] {
+        self
+    }
+
+    fn from_row(row: &[f64]) -> Self {
+        Euclidean::new(row.to_vec())
+    }
+}
+
+/// Points of a dataset, either held in memory or memory-mapped from a file of little-endian `f64` rows.
+pub(crate) enum Points<T> {
+    Memory(Vec<T>),
+    Mapped {
+        mmap: memmap2::Mmap,
+        len: usize,
+        dim: usize,
+        from_row: fn(&[f64]) -> T,
+        row: fn(&T) -> &[f64],
+    },
+}
+
+impl<T> Points<T> {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Points::Memory(points) => points.len(),
+            Points::Mapped { len, .. } => *len,
+        }
+    }
+
+    /// Check that `points` can be appended, which for mapped points means that they have the same number of dimensions.
+    pub(crate) fn check(&self, points: &[T]) -> Result<(), DiskError> {
+        if let Points::Mapped { dim, row, .. } = self {
+            if let Some(pt) = points.iter().find(|pt| row(pt).len() != *dim) {
+                return Err(DiskError::Dimension {
+                    expected: *dim,
+                    found: row(pt).len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `points`, which must have been [checked](Points::check), to the end of the point store at `path` and sync it to disk.
+    ///
+    /// The points are not part of the dataset until it is [extended](Points::extend) with them, and the write can be undone with
+    /// [`truncate`](Points::truncate) until then. If writing fails, the point store is truncated before the error is returned.
+    /// Points held in memory are not written anywhere.
+    pub(crate) fn append(&self, points: &[T], path: &Path) -> Result<(), DiskError> {
+        if let Points::Mapped { row, .. } = self {
+            let write = || -> Result<(), DiskError> {
+                let mut writer = BufWriter::new(OpenOptions::new().append(true).open(path)?);
+                for x in points.iter().flat_map(*row) {
+                    writer.write_all(&x.to_le_bytes())?;
+                }
+                writer.flush()?;
+                writer.get_ref().sync_data()?;
+                Ok(())
+            };
+            if let Err(e) = write() {
+                self.truncate(path).ok();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove any points [appended](Points::append) to the point store at `path` since it was last mapped.
+    pub(crate) fn truncate(&self, path: &Path) -> Result<(), DiskError> {
+        if let Points::Mapped { mmap, .. } = self {
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.set_len(mmap.len() as u64)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Add `points` to the dataset. Mapped points must already have been [appended](Points::append) to the point store at `path`,
+    /// which is then mapped again.
+    pub(crate) fn extend(&mut self, points: Vec<T>, path: &Path) -> Result<(), DiskError> {
+        match self {
+            Points::Memory(existing) => existing.extend(points),
+            Points::Mapped { mmap, len, .. } => {
+                *mmap = unsafe { memmap2::Mmap::map(&File::open(path)?)? };
+                *len += points.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> Points<T>
+where
+    T: Clone,
+{
+    /// The point at `idx`. Mapped points are read from disk.
+    pub(crate) fn get(&self, idx: usize) -> Cow<'_, T> {
+        match self {
+            Points::Memory(points) => Cow::Borrowed(&points[idx]),
+            Points::Mapped {
+                mmap,
+                dim,
+                from_row,
+                ..
+            } => {
+                let row = mmap[idx * dim * 8..(idx + 1) * dim * 8]
+                    .chunks_exact(8)
+                    .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect::<Vec<_>>();
+                Cow::Owned(from_row(&row))
+            }
+        }
+    }
+
+    /// The points, if they are held in memory.
+    pub(crate) fn in_memory(&self) -> Option<&[T]> {
+        match self {
+            Points::Memory(points) => Some(points),
+            Points::Mapped { .. } => None,
+        }
+    }
+}
+
+impl<T> Points<T>
+where
+    T: FixedDimension,
+{
+    /// Write `points` to a new file at `path` and map it into memory.
+    pub(crate) fn store(points: &[T], path: &Path) -> Result<Self, DiskError> {
+        let mut writer = RowWriter::create(path)?;
+        points.iter().try_for_each(|pt| writer.push(pt))?;
+        writer.finish()
+    }
+
+    /// Map an existing file of `len` points at `path` into memory, read-only.
+    ///
+    /// Returns [`DiskError::Dimension`] if the points in the file do not have the [dimensions](FixedDimension::dimensions) of `T`.
+    pub(crate) fn map(path: &Path, len: usize) -> Result<Self, DiskError> {
+        let mmap = unsafe { memmap2::Mmap::map(&File::open(path)?)? };
+        if len == 0 || mmap.len() % (len * 8) != 0 {
+            return Err(DiskError::Manifest(crate::PersistError::Corrupt));
+        }
+        let dim = mmap.len() / (len * 8);
+        if let Some(expected) = T::dimensions().filter(|&expected| expected != dim) {
+            return Err(DiskError::Dimension {
+                expected,
+                found: dim,
+            });
+        }
+
+        Ok(Points::Mapped {
+            dim,
+            mmap,
+            len,
+            from_row: T::from_row,
+            row: T::row,
+        })
+    }
+}
+
+/// Writes a point store one point at a time.
+pub(crate) struct RowWriter {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    dim: Option<usize>,
+    len: usize,
+}
+
+impl RowWriter {
+    /// Create a new point store at `path`.
+    pub(crate) fn create(path: &Path) -> Result<Self, DiskError> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            path: path.to_owned(),
+            dim: None,
+            len: 0,
+        })
+    }
+
+    /// Append a point, which must have the same number of dimensions as the first one.
+    pub(crate) fn push<T>(&mut self, pt: &T) -> Result<(), DiskError>
+    where
+        T: FixedDimension,
+    {
+        let row = pt.row();
+        let dim = *self.dim.get_or_insert(row.len());
+        if row.len() != dim {
+            return Err(DiskError::Dimension {
+                expected: dim,
+                found: row.len(),
+            });
+        }
+
+        for x in row {
+            self.writer.write_all(&x.to_le_bytes())?;
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Close the point store and map it into memory.
+    pub(crate) fn finish<T>(mut self) -> Result<Points<T>, DiskError>
+    where
+        T: FixedDimension,
+    {
+        self.writer.flush()?;
+        drop(self.writer);
+        Points::map(&self.path, self.len)
+    }
+}